@@ -1,13 +1,13 @@
-use num_cpus;
-
-use bitonic_sorter::SortOrder;
-// 第三段階の sort 関数を seq_sort という別名で使用する
-use bitonic_sorter::third::sort as seq_sort;
-// 第四段階の sort 関数を per_sort という別名で使用する
-use bitonic_sorter::fourth::sort as par_sort;
+use bitonic_sorter::fourth::{self, BaseCase, Hybrid, ParallelSortConfig};
+use bitonic_sorter::{SortError, SortOrder};
+// 第三段階の順次ソートと第四段階の並列ソートを、共通の Sorter トレイト経由で使用する
+use bitonic_sorter::sorter::{
+    OddEvenSorter, ParOddEvenSorter, ParSorter, SeqSorter, SimdSorter, Sorter,
+};
 use bitonic_sorter::utils::{is_sorted_ascending, new_u32_vec};
 
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
 use std::{env, f64};
 
@@ -24,7 +24,7 @@ fn main() {
         // ステータスコード1で終了する
         eprintln!(
             "Usage {} <number of elements in bits>",
-            env::args().nth(0).unwrap()
+            env::args().next().unwrap()
         );
         std::process::exit(1);
     }
@@ -52,25 +52,110 @@ fn run_sorts(bits: u32) {
     );
 
     // 順次ソートを実行して、処理にかかった時間を得る
-    let seq_duration = timed_sort(&seq_sort, len, "seq_sort");
+    let seq_duration = timed_sort(&SeqSorter, len, "seq_sort");
 
     // 並列ソートを実行して、処理にかかった時間を得る
-    let par_duration = timed_sort(&par_sort, len, "par_sort");
+    // u32 の fourth::sort は SIMD 版に切り替わるので、comparator を渡してスカラー版を計測する
+    let par_duration = timed(len, "par_sort", |x| fourth::sort_by(x, &|a, b| a.cmp(b)));
+
+    // SIMD 命令を使う順次ソートを実行して、処理にかかった時間を得る
+    let simd_duration = timed_sort(&SimdSorter, len, "simd_sort");
+
+    // SIMD 命令を使う並列ソート（u32 に対する fourth::sort）を実行して、処理にかかった時間を得る
+    let par_simd_duration = timed(len, "par_simd_sort", |x| {
+        fourth::sort(x, &SortOrder::Ascending)
+    });
 
     // 並列ソートが順次ソートに対して何倍速かったのか表示する
     println!("spped up: {:.2}x", seq_duration / par_duration);
+    // SIMD 版がスカラー版の順次ソート（third::sort）に対して何倍速かったのか表示する
+    println!("simd spped up: {:.2}x", seq_duration / simd_duration);
+    println!(
+        "par simd spped up: {:.2}x",
+        seq_duration / par_simd_duration
+    );
+
+    // 同じ入力で、奇偶マージソートの順次版と並列版を実行する
+    let odd_even_duration = timed_sort(&OddEvenSorter, len, "odd_even_sort");
+    let par_odd_even_duration = timed_sort(&ParOddEvenSorter, len, "par_odd_even_sort");
+    println!(
+        "odd-even vs bitonic: {:.2}x (seq), {:.2}x (par)",
+        seq_duration / odd_even_duration,
+        par_duration / par_odd_even_duration
+    );
+
+    // ハイブリッドモードの並列ソートを、素の並列ソート（comparator を使う fourth::sort_by）と比べる
+    for &(base_case, name) in &[
+        (BaseCase::InsertionSort, "hybrid_insertion_sort"),
+        (BaseCase::SortUnstable, "hybrid_sort_unstable"),
+    ] {
+        let sorter = ParSorter::new(ParallelSortConfig {
+            hybrid: Some(Hybrid {
+                block_size: 32,
+                base_case,
+            }),
+            ..Default::default()
+        });
+        let hybrid_duration = timed_sort(&sorter, len, name);
+        println!(
+            "{} vs par_sort: {:.2}x",
+            name,
+            par_duration / hybrid_duration
+        );
+    }
+
+    // ネットワークの比較器の数は入力によらないので、比較した回数を数えれば比べられる
+    let bitonic_comparisons = count_comparisons(&SeqSorter, len);
+    let odd_even_comparisons = count_comparisons(&OddEvenSorter, len);
+    println!(
+        "comparisons: bitonic {}, odd-even {} ({:.1}% fewer)",
+        bitonic_comparisons,
+        odd_even_comparisons,
+        (1.0 - odd_even_comparisons as f64 / bitonic_comparisons as f64) * 100.0
+    );
+
+    // 計測モードで比較、交換、マージのステップ、タスクを分けた回数を表示する
+    let mut x = new_u32_vec(len);
+    let seq_stats = SeqSorter
+        .sort_by_with_stats(&mut x, &|a, b| a.cmp(b))
+        .expect("Failed to sort: ");
+    println!("seq_sort stats: {:?}", seq_stats);
+    let mut x = new_u32_vec(len);
+    let par_stats = ParSorter::default()
+        .sort_by_with_stats(&mut x, &|a, b| a.cmp(b))
+        .expect("Failed to sort: ");
+    println!("par_sort stats: {:?}", par_stats);
+}
+
+// comparator が呼ばれた回数を数えながらソートする
+fn count_comparisons(sorter: &dyn Sorter<u32>, len: usize) -> usize {
+    let count = AtomicUsize::new(0);
+    let mut x = new_u32_vec(len);
+    sorter
+        .sort_by(&mut x, &|a, b| {
+            count.fetch_add(1, Ordering::Relaxed);
+            a.cmp(b)
+        })
+        .expect("Failed to sort: ");
+    count.load(Ordering::Relaxed)
+}
+
+// ソーターはトレイトオブジェクトとして受け取るので、アルゴリズムを差し替えられる
+fn timed_sort(sorter: &dyn Sorter<u32>, len: usize, name: &str) -> f64 {
+    timed(len, name, |x| sorter.sort(x, &SortOrder::Ascending))
 }
 
-fn timed_sort<F>(sorter: &F, len: usize, name: &str) -> f64
+// Sorter を実装していないソート関数も、クロージャで渡せば同じように計測できる
+fn timed<F>(len: usize, name: &str, sort: F) -> f64
 where
-    F: Fn(&mut [u32], &SortOrder) -> Result<(), String>,
+    F: Fn(&mut [u32]) -> Result<(), SortError>,
 {
     // 要素数 len の u32ベクタを生成する
     let mut x = new_u32_vec(len);
     // sorter 関数を呼び出すことで、ソートを実行する
     // かかった時間(dur)を記録する
     let start = Instant::now();
-    sorter(&mut x, &SortOrder::Ascending).expect("Failed to sort: ");
+    sort(&mut x).expect("Failed to sort: ");
     let dur = start.elapsed();
 
     let nano_secs = dur.subsec_nanos() as f64 + dur.as_secs() as f64 * 1e9_f64;
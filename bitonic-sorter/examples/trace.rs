@@ -0,0 +1,56 @@
+// ソート中の compare_and_swap を記録し、端末のアニメーションか SVG として再生する
+// 例：
+// cargo run --example trace -- 16 ansi
+// cargo run --example trace -- 64 svg par > trace.svg
+use bitonic_sorter::fourth::ParallelSortConfig;
+use bitonic_sorter::trace::{self, Trace};
+use bitonic_sorter::utils::new_u32_vec;
+use bitonic_sorter::{third, SortOrder};
+
+use std::env;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!(
+            "Usage {} <number of elements> <ansi|svg> [seq|par]",
+            args[0]
+        );
+        std::process::exit(1);
+    }
+    let len = usize::from_str(&args[1]).expect("error parsing argument");
+    let mut x = new_u32_vec(len);
+
+    let trace: Trace<u32> = match args.get(3).map(String::as_str) {
+        // 並列版では、要素数が小さくてもスレッドに分かれるように閾値を下げる
+        Some("par") => {
+            let config = ParallelSortConfig {
+                sort_threshold: 8,
+                sub_sort_threshold: 8,
+                ..Default::default()
+            };
+            trace::par_sort_traced(&mut x, &SortOrder::Ascending, &config)
+        }
+        _ => third::sort_traced(&mut x, &SortOrder::Ascending),
+    }
+    .expect("Failed to sort: ");
+
+    // 要素数が trace::MAX_REPLAY_LEN を超えると、フレームが多すぎて再生できずにエラーになる
+    match args[2].as_str() {
+        "svg" => print!("{}", trace.to_svg(50).expect("Failed to replay: ")),
+        _ => {
+            let stdout = io::stdout();
+            trace
+                .play_ansi(&mut stdout.lock(), Duration::from_millis(50))
+                .expect("Failed to replay: ");
+            println!(
+                "{} compare-and-swaps on {} threads",
+                trace.events.len(),
+                trace.thread_count()
+            );
+        }
+    }
+}
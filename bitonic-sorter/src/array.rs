@@ -0,0 +1,416 @@
+// 固定長の配列 [T; N] 向けのバイトニックソート
+// third::do_sort はスライスを再帰的に分割するが、N が小さいとその分割のコストが目立つ
+// そこで N が 2, 4, 8, 16, 32 のときは、マクロでネットワークをコンパイル時に展開し、
+// ループも再帰もない比較と交換の列にする。インデックスはすべて定数になる
+// 比較と交換を行う関数は $cas で渡す。u32 などの Copy 型には、分岐しない min/max 版を使える
+use super::{third, SortOrder};
+use std::cmp::{self, Ordering};
+
+// マクロは使う場所より前で定義しておく必要がある
+// third::do_sort と同じ再帰を、要素数ごとのパターンでマクロ展開する
+// $lo は配列内の開始位置。展開後は (0 + 4) + 2 のような定数式になる
+macro_rules! bitonic_sort {
+    ($cas:ident, $x:ident, $lo:expr, 2, $forward:expr) => {
+        bitonic_merge!($cas, $x, $lo, 2, $forward)
+    };
+    ($cas:ident, $x:ident, $lo:expr, 4, $forward:expr) => {{
+        bitonic_sort!($cas, $x, $lo, 2, !$forward);
+        bitonic_sort!($cas, $x, $lo + 2, 2, $forward);
+        bitonic_merge!($cas, $x, $lo, 4, $forward);
+    }};
+    ($cas:ident, $x:ident, $lo:expr, 8, $forward:expr) => {{
+        bitonic_sort!($cas, $x, $lo, 4, !$forward);
+        bitonic_sort!($cas, $x, $lo + 4, 4, $forward);
+        bitonic_merge!($cas, $x, $lo, 8, $forward);
+    }};
+    ($cas:ident, $x:ident, $lo:expr, 16, $forward:expr) => {{
+        bitonic_sort!($cas, $x, $lo, 8, !$forward);
+        bitonic_sort!($cas, $x, $lo + 8, 8, $forward);
+        bitonic_merge!($cas, $x, $lo, 16, $forward);
+    }};
+    ($cas:ident, $x:ident, $lo:expr, 32, $forward:expr) => {{
+        bitonic_sort!($cas, $x, $lo, 16, !$forward);
+        bitonic_sort!($cas, $x, $lo + 16, 16, $forward);
+        bitonic_merge!($cas, $x, $lo, 32, $forward);
+    }};
+}
+
+// third::sub_sort に対応する。前半と後半の対応する要素を比較してから、それぞれをマージする
+macro_rules! bitonic_merge {
+    ($cas:ident, $x:ident, $lo:expr, 2, $forward:expr) => {
+        compare_pairs!($cas, $x, $lo, 1, 1, $forward)
+    };
+    ($cas:ident, $x:ident, $lo:expr, 4, $forward:expr) => {{
+        compare_pairs!($cas, $x, $lo, 2, 2, $forward);
+        bitonic_merge!($cas, $x, $lo, 2, $forward);
+        bitonic_merge!($cas, $x, $lo + 2, 2, $forward);
+    }};
+    ($cas:ident, $x:ident, $lo:expr, 8, $forward:expr) => {{
+        compare_pairs!($cas, $x, $lo, 4, 4, $forward);
+        bitonic_merge!($cas, $x, $lo, 4, $forward);
+        bitonic_merge!($cas, $x, $lo + 4, 4, $forward);
+    }};
+    ($cas:ident, $x:ident, $lo:expr, 16, $forward:expr) => {{
+        compare_pairs!($cas, $x, $lo, 8, 8, $forward);
+        bitonic_merge!($cas, $x, $lo, 8, $forward);
+        bitonic_merge!($cas, $x, $lo + 8, 8, $forward);
+    }};
+    ($cas:ident, $x:ident, $lo:expr, 32, $forward:expr) => {{
+        compare_pairs!($cas, $x, $lo, 16, 16, $forward);
+        bitonic_merge!($cas, $x, $lo, 16, $forward);
+        bitonic_merge!($cas, $x, $lo + 16, 16, $forward);
+    }};
+}
+
+// third::compare_and_swap に対応する
+// x[$lo + i] と x[$lo + i + $distance] を、i = 0..$count について比較する
+macro_rules! compare_pairs {
+    ($cas:ident, $x:ident, $lo:expr, 1, $distance:tt, $forward:expr) => {
+        $cas($x, $lo, $lo + $distance, $forward)
+    };
+    ($cas:ident, $x:ident, $lo:expr, 2, $distance:tt, $forward:expr) => {{
+        compare_pairs!($cas, $x, $lo, 1, $distance, $forward);
+        compare_pairs!($cas, $x, $lo + 1, 1, $distance, $forward);
+    }};
+    ($cas:ident, $x:ident, $lo:expr, 4, $distance:tt, $forward:expr) => {{
+        compare_pairs!($cas, $x, $lo, 2, $distance, $forward);
+        compare_pairs!($cas, $x, $lo + 2, 2, $distance, $forward);
+    }};
+    ($cas:ident, $x:ident, $lo:expr, 8, $distance:tt, $forward:expr) => {{
+        compare_pairs!($cas, $x, $lo, 4, $distance, $forward);
+        compare_pairs!($cas, $x, $lo + 4, 4, $distance, $forward);
+    }};
+    ($cas:ident, $x:ident, $lo:expr, 16, $distance:tt, $forward:expr) => {{
+        compare_pairs!($cas, $x, $lo, 8, $distance, $forward);
+        compare_pairs!($cas, $x, $lo + 8, 8, $distance, $forward);
+    }};
+}
+
+// N が 2, 4, 8, 16, 32 なら展開したネットワークで、それ以外なら third::do_sort でソートする
+macro_rules! sort_network {
+    ($cas:ident, $x:ident, $order:expr) => {{
+        let forward = match *$order {
+            SortOrder::Ascending => true,
+            SortOrder::Descending => false,
+        };
+        let $x = &mut $x[..];
+        // N はコンパイル時に決まるので、この match は単相化のときに1つの枝だけが残る
+        match N {
+            0 | 1 => {}
+            2 => bitonic_sort!($cas, $x, 0, 2, forward),
+            4 => bitonic_sort!($cas, $x, 0, 4, forward),
+            8 => bitonic_sort!($cas, $x, 0, 8, forward),
+            16 => bitonic_sort!($cas, $x, 0, 16, forward),
+            32 => bitonic_sort!($cas, $x, 0, 32, forward),
+            // 展開しない N は third の再帰でソートする。Ord::cmp のパニックはそのまま呼び出し元に伝わる
+            _ => third::do_sort($x, forward, &|a: &T, b: &T| a.cmp(b), ()),
+        }
+    }};
+}
+
+// 配列を order の順にソートする
+pub fn sort_array<T: Ord, const N: usize>(x: &mut [T; N], order: &SortOrder) {
+    sort_network!(compare_and_swap, x, order)
+}
+
+// u32 などの Copy 型（プリミティブ型）向けの sort_array
+// 比較と交換を min/max で行うので、交換するかどうかで分岐しない（条件付き移動命令になる）
+// 結果は sort_array と同じ（等しい要素も交換しない）
+pub fn sort_array_copy<T: Ord + Copy, const N: usize>(x: &mut [T; N], order: &SortOrder) {
+    sort_network!(compare_and_swap_copy, x, order)
+}
+
+// x[i] と x[j] を比較し、forward なら小さい方を、そうでなければ大きい方を x[i] に置く
+#[inline(always)]
+fn compare_and_swap<T: Ord>(x: &mut [T], i: usize, j: usize, forward: bool) {
+    let swap_condition = if forward {
+        Ordering::Greater
+    } else {
+        Ordering::Less
+    };
+    if x[i].cmp(&x[j]) == swap_condition {
+        x.swap(i, j);
+    }
+}
+
+// compare_and_swap の min/max 版
+// cmp::min は等しければ1つ目の引数を、cmp::max は2つ目の引数を返すので、
+// 引数の順序をそろえれば、等しい要素を交換しない compare_and_swap と同じ結果になる
+#[inline(always)]
+fn compare_and_swap_copy<T: Ord + Copy>(x: &mut [T], i: usize, j: usize, forward: bool) {
+    let (a, b) = (x[i], x[j]);
+    if forward {
+        x[i] = cmp::min(a, b);
+        x[j] = cmp::max(a, b);
+    } else {
+        x[i] = cmp::max(b, a);
+        x[j] = cmp::min(b, a);
+    }
+}
+
+// このモジュールは cargo test を実行したときのみコンパイルされる
+#[cfg(test)]
+mod tests {
+    use super::{sort_array, sort_array_copy};
+    use crate::network::{Comparator, Network};
+    use crate::third;
+    use crate::utils::new_u32_vec;
+    use crate::verify::{find_counterexample_random, is_sorting_network, network_from_sort};
+    use crate::SortOrder::{self, *};
+    use std::cell::Cell;
+    use std::cmp::Ordering;
+    use std::convert::TryInto;
+    use std::panic::{self, AssertUnwindSafe};
+
+    // 乱数で N 要素の配列を作り、third::sort と同じ結果になることを確かめる
+    fn check<const N: usize>() {
+        for order in &[Ascending, Descending] {
+            let mut expected = new_u32_vec(N);
+            let mut x: [u32; N] = expected.clone().try_into().unwrap();
+            let mut y = x;
+            sort_array(&mut x, order);
+            sort_array_copy(&mut y, order);
+            third::sort(&mut expected, order).unwrap();
+            assert_eq!(x.to_vec(), expected);
+            assert_eq!(y.to_vec(), expected);
+        }
+    }
+
+    #[test]
+    fn sort_copy_keeps_equal_elements_in_place() {
+        // キーだけを比較する Copy 型。min/max 版でも、等しい要素の順序は sort_array と一致する
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        struct Keyed(u32, usize);
+        impl PartialOrd for Keyed {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for Keyed {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.0.cmp(&other.0)
+            }
+        }
+
+        for order in &[Ascending, Descending] {
+            let values = new_u32_vec(32);
+            let mut x: [Keyed; 32] = values
+                .iter()
+                .zip(0..)
+                .map(|(&v, i)| Keyed(v % 3, i))
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap();
+            let mut y = x;
+            sort_array(&mut x, order);
+            sort_array_copy(&mut y, order);
+            assert_eq!(x, y);
+        }
+    }
+
+    #[test]
+    fn sort_unrolled_sizes() {
+        check::<2>();
+        check::<4>();
+        check::<8>();
+        check::<16>();
+        check::<32>();
+    }
+
+    // 比較すると comparator を呼ぶ要素。id は要素の元の位置
+    #[derive(Clone, Copy)]
+    struct Probe<'a> {
+        id: usize,
+        comparator: &'a dyn Fn(&usize, &usize) -> Ordering,
+    }
+    impl PartialEq for Probe<'_> {
+        fn eq(&self, other: &Self) -> bool {
+            self.cmp(other) == Ordering::Equal
+        }
+    }
+    impl Eq for Probe<'_> {}
+    impl PartialOrd for Probe<'_> {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+    impl Ord for Probe<'_> {
+        fn cmp(&self, other: &Self) -> Ordering {
+            (self.comparator)(&self.id, &other.id)
+        }
+    }
+
+    // sort_array が N 要素の配列に対して実行する比較器の列を、昇順にソートするネットワークとして返す
+    // 降順のネットワークは、位置を左右反転すると昇順のネットワークになる
+    fn unrolled_network<const N: usize>(order: &SortOrder) -> Network {
+        let network = network_from_sort(N, |x, comparator| {
+            let mut probes: [Probe; N] = std::array::from_fn(|i| Probe {
+                id: x[i],
+                comparator,
+            });
+            sort_array(&mut probes, order);
+            for (v, p) in x.iter_mut().zip(&probes) {
+                *v = p.id;
+            }
+        });
+        match *order {
+            Ascending => network,
+            Descending => {
+                // (x[i], x[j]) が降順なら、反転した位置 (N-1-j, N-1-i) の2要素は昇順
+                let mirrored: Vec<Comparator> = network
+                    .comparators()
+                    .map(|c| Comparator {
+                        i: N - 1 - c.j,
+                        j: N - 1 - c.i,
+                        order: match c.order {
+                            Ascending => Descending,
+                            Descending => Ascending,
+                        },
+                    })
+                    .collect();
+                Network::from_comparators(N, &mirrored)
+            }
+        }
+    }
+
+    #[test]
+    fn unrolled_networks_sort_all_inputs() {
+        // 0-1原理で、展開したネットワークがすべての入力をソートできることを確かめる
+        for order in &[Ascending, Descending] {
+            assert!(is_sorting_network(&unrolled_network::<2>(order)));
+            assert!(is_sorting_network(&unrolled_network::<4>(order)));
+            assert!(is_sorting_network(&unrolled_network::<8>(order)));
+            assert!(is_sorting_network(&unrolled_network::<16>(order)));
+            // 32要素の 2^32 通りの入力はテストで試すには多すぎるので、ランダムな入力で確かめ、
+            // 比較器の列が third::sort_by と同じ（Network::bitonic）であることも確かめる
+            let network = unrolled_network::<32>(order);
+            assert_eq!(find_counterexample_random(&network, 1024, 0), None);
+            if *order == Ascending {
+                assert_eq!(network, Network::bitonic(32));
+            }
+        }
+    }
+
+    #[test]
+    fn sort_other_sizes() {
+        check::<0>();
+        check::<1>();
+        check::<5>();
+        check::<64>();
+    }
+
+    #[test]
+    fn sort_32_ascending() {
+        let mut x: [u32; 8] = [10, 30, 11, 20, 4, 330, 21, 110];
+        sort_array(&mut x, &Ascending);
+        assert_eq!(x, [4, 10, 11, 20, 21, 30, 110, 330]);
+    }
+
+    #[test]
+    fn sort_strings_descending() {
+        // Copy でない型でも、要素が失われたり複製されたりしない
+        let mut x = [
+            "Rust".to_string(),
+            "is".to_string(),
+            "fast".to_string(),
+            "and".to_string(),
+        ];
+        sort_array(&mut x, &Descending);
+        assert_eq!(x, ["is", "fast", "and", "Rust"]);
+    }
+
+    // 解放された回数を数える型。panic_at 回目の比較でパニックする
+    struct Tracked<'a> {
+        value: u32,
+        drops: &'a Cell<usize>,
+        calls: &'a Cell<usize>,
+        panic_at: usize,
+    }
+
+    impl Drop for Tracked<'_> {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    impl PartialEq for Tracked<'_> {
+        fn eq(&self, other: &Self) -> bool {
+            self.value == other.value
+        }
+    }
+
+    impl Eq for Tracked<'_> {}
+
+    impl PartialOrd for Tracked<'_> {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Tracked<'_> {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.calls.set(self.calls.get() + 1);
+            if self.calls.get() == self.panic_at {
+                panic!("injected panic");
+            }
+            self.value.cmp(&other.value)
+        }
+    }
+
+    #[test]
+    fn panic_does_not_double_drop() {
+        // 16要素のネットワークの比較器は 80個。どの比較でパニックしても、
+        // 要素が二重に解放されたり、失われたりしない
+        for panic_at in 1..=80 {
+            let drops = Cell::new(0);
+            let calls = Cell::new(0);
+            let values = new_u32_vec(16);
+            let mut x: [Tracked; 16] = values
+                .iter()
+                .map(|&value| Tracked {
+                    value,
+                    drops: &drops,
+                    calls: &calls,
+                    panic_at,
+                })
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap_or_else(|_| unreachable!());
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| sort_array(&mut x, &Ascending)));
+            assert!(result.is_err());
+            assert_eq!(drops.get(), 0);
+
+            let mut sorted: Vec<u32> = x.iter().map(|t| t.value).collect();
+            sorted.sort();
+            let mut expected = values;
+            expected.sort();
+            assert_eq!(sorted, expected);
+
+            drop(x);
+            assert_eq!(drops.get(), 16);
+        }
+    }
+
+    #[test]
+    fn panic_passes_through_for_other_sizes() {
+        // 展開しない N でも、Ord::cmp のパニックは元のメッセージのまま伝わる
+        let drops = Cell::new(0);
+        let calls = Cell::new(0);
+        let mut x: [Tracked; 5] = new_u32_vec(5)
+            .into_iter()
+            .map(|value| Tracked {
+                value,
+                drops: &drops,
+                calls: &calls,
+                panic_at: 3,
+            })
+            .collect::<Vec<_>>()
+            .try_into()
+            .unwrap_or_else(|_| unreachable!());
+        let payload =
+            panic::catch_unwind(AssertUnwindSafe(|| sort_array(&mut x, &Ascending))).unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"injected panic"));
+        assert_eq!(drops.get(), 0);
+    }
+}
@@ -0,0 +1,145 @@
+// 浮動小数点数（f32、f64）のソートで使う比較
+// f32 と f64 は NaN があるため Ord を実装しておらず、partial_cmp().unwrap() で比較すると
+// NaN でパニックする。そこで IEEE 754 の totalOrder に従って比較し、NaN の位置と
+// -0.0 と +0.0 の扱いを FloatOrder で選べるようにする
+use super::SortOrder;
+use std::cmp::Ordering;
+
+// ソートできる浮動小数点数の型を表すトレイト
+// 外部のクレートで実装されないよう、private モジュールの Sealed トレイトを継承させる
+pub trait Float: Copy + Send + Sync + private::Sealed {
+    // IEEE 754 の totalOrder による比較
+    // -NaN < -∞ < ... < -0.0 < +0.0 < ... < +∞ < +NaN の順になる
+    fn total_cmp(&self, other: &Self) -> Ordering;
+    fn is_nan(self) -> bool;
+    // -0.0 か +0.0 なら true
+    fn is_zero(self) -> bool;
+}
+
+mod private {
+    pub trait Sealed {}
+    impl Sealed for f32 {}
+    impl Sealed for f64 {}
+}
+
+macro_rules! impl_float {
+    ($t:ty) => {
+        impl Float for $t {
+            fn total_cmp(&self, other: &Self) -> Ordering {
+                <$t>::total_cmp(self, other)
+            }
+
+            fn is_nan(self) -> bool {
+                <$t>::is_nan(self)
+            }
+
+            fn is_zero(self) -> bool {
+                self == 0.0
+            }
+        }
+    };
+}
+
+impl_float!(f32);
+impl_float!(f64);
+
+// NaN を置く位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NanPlacement {
+    // totalOrder に従う。昇順なら符号ビットが立った NaN は先頭に、それ以外の NaN は末尾に来る
+    TotalOrder,
+    // ソート順によらず、すべての NaN を先頭に置く
+    First,
+    // ソート順によらず、すべての NaN を末尾に置く
+    Last,
+}
+
+// -0.0 と +0.0 の扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroOrder {
+    // totalOrder に従い、-0.0 を +0.0 より小さいとみなす
+    NegativeFirst,
+    // -0.0 と +0.0 を等しいとみなす（== と同じ）。両者の順序は保証しない
+    Equal,
+}
+
+// 浮動小数点数の比較方法。Default は IEEE 754 の totalOrder そのもの
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FloatOrder {
+    pub nan: NanPlacement,
+    pub zero: ZeroOrder,
+}
+
+impl Default for FloatOrder {
+    fn default() -> Self {
+        Self {
+            nan: NanPlacement::TotalOrder,
+            zero: ZeroOrder::NegativeFirst,
+        }
+    }
+}
+
+impl FloatOrder {
+    // order の順にソートするときの a と b の比較結果を返す
+    // この比較は全順序なので、third や fourth の sort_by にそのまま渡せる
+    pub fn compare<T: Float>(&self, a: &T, b: &T, order: &SortOrder) -> Ordering {
+        let (a_nan, b_nan) = (a.is_nan(), b.is_nan());
+        if self.nan != NanPlacement::TotalOrder && (a_nan || b_nan) {
+            // NaN どうしは等しいとみなす。NaN の位置はソート順によらない
+            let nan_last = a_nan.cmp(&b_nan);
+            return match self.nan {
+                NanPlacement::First => nan_last.reverse(),
+                _ => nan_last,
+            };
+        }
+
+        let ordering = if self.zero == ZeroOrder::Equal && a.is_zero() && b.is_zero() {
+            Ordering::Equal
+        } else {
+            a.total_cmp(b)
+        };
+        match *order {
+            SortOrder::Ascending => ordering,
+            SortOrder::Descending => ordering.reverse(),
+        }
+    }
+}
+
+// このモジュールは cargo test を実行したときのみコンパイルされる
+#[cfg(test)]
+mod tests {
+    use super::{FloatOrder, NanPlacement, ZeroOrder};
+    use crate::SortOrder::*;
+    use std::cmp::Ordering;
+
+    #[test]
+    fn compare_total_order() {
+        let order = FloatOrder::default();
+        assert_eq!(order.compare(&-0.0, &0.0, &Ascending), Ordering::Less);
+        assert_eq!(order.compare(&-0.0, &0.0, &Descending), Ordering::Greater);
+        assert_eq!(
+            order.compare(&f64::NAN, &f64::INFINITY, &Ascending),
+            Ordering::Greater
+        );
+        assert_eq!(
+            order.compare(&-f64::NAN, &f64::NEG_INFINITY, &Ascending),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn compare_nan_and_zero_options() {
+        let order = FloatOrder {
+            nan: NanPlacement::First,
+            zero: ZeroOrder::Equal,
+        };
+        assert_eq!(order.compare(&-0.0f32, &0.0, &Ascending), Ordering::Equal);
+        // NaN はソート順によらず先頭に来る
+        assert_eq!(order.compare(&f32::NAN, &1.0, &Ascending), Ordering::Less);
+        assert_eq!(order.compare(&f32::NAN, &1.0, &Descending), Ordering::Less);
+        assert_eq!(
+            order.compare(&-f32::NAN, &f32::NAN, &Ascending),
+            Ordering::Equal
+        );
+    }
+}
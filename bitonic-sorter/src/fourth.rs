@@ -5,45 +5,925 @@
 //   u32 型は32ビット符号なし整数
 //   [u32] 型はu32のスライス（現時点ではスライスは1次元の配列と考えてよい）
 // 型パラメータＴにトレイト境界Ord（全順序）を追加する
-use super::SortOrder;
+use super::float::{Float, FloatOrder};
+use super::stats::{SortStats, StatsCounter};
+use super::{catching, simd, SortError, SortOrder};
+use crate::utils::{apply_permutation, new_u32_vec};
 use rayon;
-use std::cmp::Ordering;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::cell::Cell;
+use std::cmp::{Ordering, Reverse};
+use std::convert::Infallible;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub fn sort_by<T, F>(x: &mut [T], comparator: &F) -> Result<(), String>
+// ソートは途中で中断できない。中断したり進み具合を表示したりするなら sort_by_cancellable を使う
+pub fn sort_by<T, F>(x: &mut [T], comparator: &F) -> Result<(), SortError>
 where
     T: Send,
     F: Sync + Fn(&T, &T) -> Ordering,
 {
-    if x.len().is_power_of_two() {
-        do_sort(x, true, comparator);
-        Ok(())
-    } else {
-        Err(format!(
-            "The length of x is not a power of two. (x.len(): {})",
-            x.len()
-        ))
+    sort_by_with_config(x, comparator, &ParallelSortConfig::default())
+}
+
+// 安定ソート。argsort_by でインデックスの列を並列にソートし、最後に x を並べ替える
+// 並列にソートしている間、各スレッドが x を参照するので T には Sync 境界も必要
+pub fn sort_by_stable<T, F>(x: &mut [T], comparator: &F) -> Result<(), SortError>
+where
+    T: Send + Sync,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    sort_by_stable_with_config(x, comparator, &ParallelSortConfig::default())
+}
+
+// sort_by_stable の config を指定できる版
+pub fn sort_by_stable_with_config<T, F>(
+    x: &mut [T],
+    comparator: &F,
+    config: &ParallelSortConfig,
+) -> Result<(), SortError>
+where
+    T: Send + Sync,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    let indices = argsort_by_with_config(x, comparator, config)?;
+    apply_permutation(x, &indices);
+    Ok(())
+}
+
+// x をソートしたときに i 番目に来る要素の元のインデックスを、i 番目に持つベクタを返す
+// インデックスの列を並列にソートする。順序は third::argsort_by と同じ
+pub fn argsort_by<T, F>(x: &[T], comparator: &F) -> Result<Vec<usize>, SortError>
+where
+    T: Sync,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    argsort_by_with_config(x, comparator, &ParallelSortConfig::default())
+}
+
+// argsort_by の config を指定できる版
+pub fn argsort_by_with_config<T, F>(
+    x: &[T],
+    comparator: &F,
+    config: &ParallelSortConfig,
+) -> Result<Vec<usize>, SortError>
+where
+    T: Sync,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    let mut indices: Vec<usize> = (0..x.len()).collect();
+    sort_by_with_config(
+        &mut indices,
+        &|&i, &j| comparator(&x[i], &x[j]).then_with(|| i.cmp(&j)),
+        config,
+    )?;
+    Ok(indices)
+}
+
+pub fn argsort<T: Ord + Sync>(x: &[T], order: &SortOrder) -> Result<Vec<usize>, SortError> {
+    match *order {
+        SortOrder::Ascending => argsort_by(x, &|a, b| a.cmp(b)),
+        SortOrder::Descending => argsort_by(x, &|a, b| b.cmp(a)),
+    }
+}
+
+// 要素からキーを取り出して比較する。キーは比較のたびに計算される
+pub fn sort_by_key<T, K, F>(x: &mut [T], key: &F) -> Result<(), SortError>
+where
+    T: Send,
+    K: Ord,
+    F: Sync + Fn(&T) -> K,
+{
+    sort_by(x, &|a, b| key(a).cmp(&key(b)))
+}
+
+// キーは要素ごとに一度だけ、rayon の並列イテレータで計算する
+// (キー, 元の位置) の組を並列にソートしてから x を並べ替える
+pub fn sort_by_cached_key<T, K, F>(x: &mut [T], key: &F) -> Result<(), SortError>
+where
+    T: Send + Sync,
+    K: Ord + Send,
+    F: Sync + Fn(&T) -> K,
+{
+    let mut keys: Vec<(K, usize)> = x.par_iter().map(key).zip(0..x.len()).collect();
+    sort_by(&mut keys, &|a, b| a.cmp(b))?;
+    let indices: Vec<usize> = keys.into_iter().map(|(_, i)| i).collect();
+    apply_permutation(x, &indices);
+    Ok(())
+}
+
+// キーのスライスと値のスライスを、キーの順序で一緒に並列に並べ替える
+pub fn sort_pairs<K, V>(
+    keys: &mut [K],
+    values: &mut [V],
+    order: &SortOrder,
+) -> Result<(), SortError>
+where
+    K: Ord + Send,
+    V: Send,
+{
+    sort_pairs_with_config(keys, values, order, &ParallelSortConfig::default())
+}
+
+// sort_pairs の config を指定できる版
+pub fn sort_pairs_with_config<K, V>(
+    keys: &mut [K],
+    values: &mut [V],
+    order: &SortOrder,
+    config: &ParallelSortConfig,
+) -> Result<(), SortError>
+where
+    K: Ord + Send,
+    V: Send,
+{
+    if keys.len() != values.len() {
+        return Err(SortError::LengthMismatch {
+            keys: keys.len(),
+            values: values.len(),
+        });
+    }
+    let forward = match *order {
+        SortOrder::Ascending => true,
+        SortOrder::Descending => false,
+    };
+    config.install(|| do_sort_pairs(keys, values, forward, config, 0));
+    Ok(())
+}
+
+// ソート済みの x[..mid] と x[mid..] を並列にマージする。考え方は third::bitonic_merge_by と同じ
+pub fn bitonic_merge_by<T, F>(x: &mut [T], mid: usize, comparator: &F) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    bitonic_merge_by_with_config(x, mid, comparator, &ParallelSortConfig::default())
+}
+
+// bitonic_merge_by の config を指定できる版
+pub fn bitonic_merge_by_with_config<T, F>(
+    x: &mut [T],
+    mid: usize,
+    comparator: &F,
+    config: &ParallelSortConfig,
+) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    if mid > x.len() {
+        return Err(SortError::MidOutOfRange { mid, len: x.len() });
+    }
+    x[..mid].reverse();
+    config.install(|| sub_sort(x, true, comparator, config, 0));
+    Ok(())
+}
+
+pub fn bitonic_merge<T: Ord + Send>(
+    x: &mut [T],
+    mid: usize,
+    order: &SortOrder,
+) -> Result<(), SortError> {
+    match *order {
+        SortOrder::Ascending => bitonic_merge_by(x, mid, &|a, b| a.cmp(b)),
+        SortOrder::Descending => bitonic_merge_by(x, mid, &|a, b| b.cmp(a)),
+    }
+}
+
+// order の順にソート済みの a と b を並列にマージして out に書き込む
+// out へのコピーも並列に行うので、T には Sync 境界も必要
+pub fn merge_sorted<T: Ord + Clone + Send + Sync>(
+    a: &[T],
+    b: &[T],
+    out: &mut [T],
+    order: &SortOrder,
+) -> Result<(), SortError> {
+    if out.len() != a.len() + b.len() {
+        return Err(SortError::OutputLengthMismatch {
+            expected: a.len() + b.len(),
+            actual: out.len(),
+        });
+    }
+    let (first, second) = out.split_at_mut(a.len());
+    first
+        .par_iter_mut()
+        .zip(a.par_iter())
+        .for_each(|(o, v)| o.clone_from(v));
+    second
+        .par_iter_mut()
+        .zip(b.par_iter())
+        .for_each(|(o, v)| o.clone_from(v));
+    bitonic_merge(out, a.len(), order)
+}
+
+// comparator の順で先頭から k 個の要素を選び、ソートして x[..k] に置く。x[k..] の順序は不定
+// k 要素ずつのブロックを並列にソートし、2つずつ並列に組み合わせて k 個に絞り込んでいく
+pub fn partial_sort_by<T, F>(x: &mut [T], k: usize, comparator: &F) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    partial_sort_by_with_config(x, k, comparator, &ParallelSortConfig::default())
+}
+
+// partial_sort_by の config を指定できる版
+pub fn partial_sort_by_with_config<T, F>(
+    x: &mut [T],
+    k: usize,
+    comparator: &F,
+    config: &ParallelSortConfig,
+) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    config.install(|| do_partial_sort(x, k, comparator, config));
+    Ok(())
+}
+
+pub fn partial_sort<T: Ord + Send>(
+    x: &mut [T],
+    k: usize,
+    order: &SortOrder,
+) -> Result<(), SortError> {
+    match *order {
+        SortOrder::Ascending => partial_sort_by(x, k, &|a, b| a.cmp(b)),
+        SortOrder::Descending => partial_sort_by(x, k, &|a, b| b.cmp(a)),
+    }
+}
+
+// order の順で先頭から k 個の要素を、ソートしたベクタで返す。x は変更しない
+pub fn top_k<T: Ord + Clone + Send>(
+    x: &[T],
+    k: usize,
+    order: &SortOrder,
+) -> Result<Vec<T>, SortError> {
+    let mut x = x.to_vec();
+    partial_sort(&mut x, k, order)?;
+    x.truncate(k);
+    Ok(x)
+}
+
+// data を row_len 要素ずつの行に分け、それぞれの行を独立にソートする
+// 1行が PARALLEL_THRESHOLD より短いと行の中は並列化されないので、行どうしを並列にソートする
+// どの行も同じ長さなので、同じソーティングネットワークでソートされる
+pub fn sort_batch_by<T, F>(data: &mut [T], row_len: usize, comparator: &F) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    sort_batch_by_with_config(data, row_len, comparator, &ParallelSortConfig::default())
+}
+
+// sort_batch_by の config を指定できる版
+pub fn sort_batch_by_with_config<T, F>(
+    data: &mut [T],
+    row_len: usize,
+    comparator: &F,
+    config: &ParallelSortConfig,
+) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    if data.is_empty() {
+        return Ok(());
+    }
+    if row_len == 0 || data.len() % row_len != 0 {
+        return Err(SortError::RowLengthMismatch {
+            len: data.len(),
+            row_len,
+        });
+    }
+    // 行が短いときは、ひとつのタスクがおよそ sort_threshold 要素を受け持つようにまとめる
+    let min_rows = (config.sort_threshold / row_len).max(1);
+    config.install(|| {
+        data.par_chunks_mut(row_len)
+            .with_min_len(min_rows)
+            .for_each(|row| do_sort(row, true, comparator, config, 0))
+    });
+    Ok(())
+}
+
+pub fn sort_batch<T: Ord + Send>(
+    data: &mut [T],
+    row_len: usize,
+    order: &SortOrder,
+) -> Result<(), SortError> {
+    match *order {
+        SortOrder::Ascending => sort_batch_by(data, row_len, &|a, b| a.cmp(b)),
+        SortOrder::Descending => sort_batch_by(data, row_len, &|a, b| b.cmp(a)),
+    }
+}
+
+// data を offsets で区切った可変長のセグメントを、それぞれ orders の順に独立にソートする
+// offsets は CSR 形式で、i 番目のセグメントは data[offsets[i]..offsets[i + 1]]
+// （offsets[0] は 0、最後の値は data.len() で、値は減少しない）
+pub fn sort_segments_by<T, F>(
+    data: &mut [T],
+    offsets: &[usize],
+    orders: &[SortOrder],
+    comparator: &F,
+) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    sort_segments_by_with_config(
+        data,
+        offsets,
+        orders,
+        comparator,
+        &ParallelSortConfig::default(),
+    )
+}
+
+// sort_segments_by の config を指定できる版
+pub fn sort_segments_by_with_config<T, F>(
+    data: &mut [T],
+    offsets: &[usize],
+    orders: &[SortOrder],
+    comparator: &F,
+    config: &ParallelSortConfig,
+) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    let mut segments = split_segments(data, offsets, orders)?;
+
+    // 長いセグメントから先に処理すると、最後に長いセグメントだけが残って
+    // ほかのスレッドが遊ぶことが少なくなる
+    segments.sort_unstable_by_key(|(segment, _)| Reverse(segment.len()));
+
+    // 短いセグメントはまとめて、ひとつのタスクがおよそ sort_threshold 要素を受け持つようにする
+    // sort_threshold 以上のセグメントはひとつでタスクになり、do_sort の中でさらに並列化される
+    let mut tasks: Vec<Vec<(&mut [T], bool)>> = Vec::new();
+    let mut task_len = config.sort_threshold;
+    for segment in segments {
+        if task_len >= config.sort_threshold {
+            tasks.push(Vec::new());
+            task_len = 0;
+        }
+        task_len += segment.0.len();
+        tasks.last_mut().unwrap().push(segment);
+    }
+
+    config.install(|| {
+        tasks.into_par_iter().for_each(|task| {
+            for (segment, forward) in task {
+                do_sort(segment, forward, comparator, config, 0);
+            }
+        })
+    });
+    Ok(())
+}
+
+pub fn sort_segments<T: Ord + Send>(
+    data: &mut [T],
+    offsets: &[usize],
+    orders: &[SortOrder],
+) -> Result<(), SortError> {
+    sort_segments_by(data, offsets, orders, &|a, b| a.cmp(b))
+}
+
+// offsets を検査して、data をセグメントごとの可変スライスと forward の組に分ける
+fn split_segments<'a, T>(
+    mut data: &'a mut [T],
+    offsets: &[usize],
+    orders: &[SortOrder],
+) -> Result<Vec<(&'a mut [T], bool)>, SortError> {
+    if offsets.first() != Some(&0) {
+        return Err(SortError::InvalidOffsets { index: 0 });
+    }
+    if offsets.last() != Some(&data.len()) {
+        return Err(SortError::InvalidOffsets {
+            index: offsets.len() - 1,
+        });
+    }
+    if let Some(index) = (1..offsets.len()).find(|&i| offsets[i] < offsets[i - 1]) {
+        return Err(SortError::InvalidOffsets { index });
+    }
+    if orders.len() != offsets.len() - 1 {
+        return Err(SortError::SegmentCountMismatch {
+            segments: offsets.len() - 1,
+            orders: orders.len(),
+        });
+    }
+
+    let mut segments = Vec::with_capacity(orders.len());
+    for (bounds, order) in offsets.windows(2).zip(orders) {
+        // 借用チェッカーを通すため、data をいったん取り出してから分割する
+        let (segment, rest) = mem::take(&mut data).split_at_mut(bounds[1] - bounds[0]);
+        data = rest;
+        segments.push((segment, *order == SortOrder::Ascending));
+    }
+    Ok(segments)
+}
+
+pub fn sort<T: Ord + Send>(x: &mut [T], order: &SortOrder) -> Result<(), SortError> {
+    sort_with_config(x, order, &ParallelSortConfig::default())
+}
+
+// 失敗するかもしれない comparator で並列にソートする。third::try_sort_by の並列版
+// どれかのスレッドで comparator が Err を返したら、ほかのスレッドも残りの比較をやめて
+// 最初に見つかったエラーを返す。x は入力の要素を並べ替えたもののままになる
+// エラーがスレッドをまたいで返されるので、E には Send 境界が必要
+pub fn try_sort_by<T, E, F>(x: &mut [T], comparator: &F) -> Result<(), E>
+where
+    T: Send,
+    E: Send,
+    F: Sync + Fn(&T, &T) -> Result<Ordering, E>,
+{
+    try_sort_by_with_config(x, comparator, &ParallelSortConfig::default())
+}
+
+// try_sort_by の config を指定できる版
+pub fn try_sort_by_with_config<T, E, F>(
+    x: &mut [T],
+    comparator: &F,
+    config: &ParallelSortConfig,
+) -> Result<(), E>
+where
+    T: Send,
+    E: Send,
+    F: Sync + Fn(&T, &T) -> Result<Ordering, E>,
+{
+    let failed = AtomicBool::new(false);
+    config.install(|| try_do_sort(x, true, comparator, config, 0, &failed, None))
+}
+
+// 進み具合。終えたステップの数と、ステップの総数
+// ステップは、マージ（sub_sort）の比較の段のひとつ。要素数 n のブロックのマージは、
+// 比較する2要素の間隔が n 以上の最小の2のべき乗の半分、その半分、……、1 の log2 段からなる
+// ハイブリッドモードで base_case がソートするブロックは、ひとつを1ステップと数える
+// 総数は x の要素数と config.hybrid だけで決まり、並列化の閾値にはよらない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub completed: usize,
+    pub total: usize,
+}
+
+// 中断できる並列ソート
+// cancel が true になると、どのスレッドも残りの比較をやめて Err(SortError::Cancelled) を返す
+// 中断しても x は入力の要素を並べ替えたもののままになる（ソートは終わっていない）
+// progress を渡すと、ステップを終えるたびに呼ばれる。UI に進み具合を表示するのに使える
+// progress は複数のスレッドから呼ばれるので、completed の値が前後して届くことがある
+// config.hybrid と config.stats も sort_by_with_config と同じように使う
+pub fn sort_by_cancellable<T, F>(
+    x: &mut [T],
+    comparator: &F,
+    config: &ParallelSortConfig,
+    cancel: &AtomicBool,
+    progress: Option<&(dyn Fn(Progress) + Sync)>,
+) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    // 比較のたびにキャンセルトークンを確かめ、立っていれば Err にする
+    // Err を返すと try_do_sort の failed フラグが立ち、ほかのスレッドも比較をやめる
+    let checked = |a: &T, b: &T| {
+        if cancel.load(AtomicOrdering::Relaxed) {
+            Err(SortError::Cancelled)
+        } else {
+            Ok(comparator(a, b))
+        }
+    };
+    let failed = AtomicBool::new(false);
+    let reporter = progress.map(|callback| ProgressReporter::new(callback, x.len(), config));
+    config.install(|| try_do_sort(x, true, &checked, config, 0, &failed, reporter.as_ref()))
+}
+
+pub fn sort_cancellable<T: Ord + Send>(
+    x: &mut [T],
+    order: &SortOrder,
+    config: &ParallelSortConfig,
+    cancel: &AtomicBool,
+    progress: Option<&(dyn Fn(Progress) + Sync)>,
+) -> Result<(), SortError> {
+    match *order {
+        SortOrder::Ascending => sort_by_cancellable(x, &|a, b| a.cmp(b), config, cancel, progress),
+        SortOrder::Descending => sort_by_cancellable(x, &|a, b| b.cmp(a), config, cancel, progress),
+    }
+}
+
+// 終えたステップを数えて、progress に報告する
+struct ProgressReporter<'a> {
+    callback: &'a (dyn Fn(Progress) + Sync),
+    completed: AtomicUsize,
+    total: usize,
+}
+
+impl<'a> ProgressReporter<'a> {
+    fn new(
+        callback: &'a (dyn Fn(Progress) + Sync),
+        len: usize,
+        config: &ParallelSortConfig,
+    ) -> Self {
+        Self {
+            callback,
+            completed: AtomicUsize::new(0),
+            total: count_steps(len, config),
+        }
     }
+
+    // ステップをひとつ終えた
+    fn step(&self) {
+        let completed = self.completed.fetch_add(1, AtomicOrdering::Relaxed) + 1;
+        (self.callback)(Progress {
+            completed,
+            total: self.total,
+        });
+    }
+}
+
+// ひとつのマージの進み具合。段ごとに、まだ比較を終えていない sub_sort の呼び出しの数を持つ
+// 段 k は間隔 2^k で比較する sub_sort の呼び出しで、最後のひとつを終えたらステップを報告する
+struct MergeProgress<'a> {
+    reporter: &'a ProgressReporter<'a>,
+    remaining: Vec<AtomicUsize>,
+}
+
+impl<'a> MergeProgress<'a> {
+    fn new(reporter: &'a ProgressReporter<'a>, len: usize) -> Self {
+        // 間隔 d の呼び出しは、先頭から 2d 要素ずつに区切ったブロックごとにひとつと、
+        // 末尾の余りが d より長ければ（比較する相手があれば）その余りにひとつ
+        let steps = len.next_power_of_two().trailing_zeros();
+        let remaining = (0..steps)
+            .map(|k| {
+                let d = 1 << k;
+                AtomicUsize::new(len / (2 * d) + (len % (2 * d) > d) as usize)
+            })
+            .collect();
+        Self {
+            reporter,
+            remaining,
+        }
+    }
+
+    // 間隔 2^level の sub_sort の呼び出しが、比較を終えた
+    fn compared(&self, level: usize) {
+        if self.remaining[level].fetch_sub(1, AtomicOrdering::Relaxed) == 1 {
+            self.reporter.step();
+        }
+    }
+}
+
+// 要素数 len をソートするときのステップの総数
+// do_sort の再帰の各深さに現れる要素数は多くても2種類なので、要素数ごとの個数をまとめて数える
+fn count_steps(len: usize, config: &ParallelSortConfig) -> usize {
+    let mut total = 0;
+    let mut blocks = vec![(len, 1)];
+    while !blocks.is_empty() {
+        let mut next: Vec<(usize, usize)> = Vec::new();
+        for (len, count) in blocks {
+            if len <= 1 {
+                continue;
+            }
+            if config.base_case(len).is_some() {
+                total += count;
+                continue;
+            }
+            total += count * len.next_power_of_two().trailing_zeros() as usize;
+            for &half in &[len / 2, len - len / 2] {
+                match next.iter_mut().find(|(l, _)| *l == half) {
+                    Some((_, c)) => *c += count,
+                    None => next.push((half, count)),
+                }
+            }
+        }
+        blocks = next;
+    }
+    total
+}
+
+// f32 や f64 のスライスを、float_order に従って order の順に並列にソートする
+// f32 を totalOrder（FloatOrder::default()）でソートするときは、SIMD 版に切り替える
+pub fn sort_floats<T: Float>(
+    x: &mut [T],
+    order: &SortOrder,
+    float_order: &FloatOrder,
+) -> Result<(), SortError> {
+    if *float_order == FloatOrder::default() {
+        if let Some(x) = simd::downcast_slice_mut::<T, f32>(x) {
+            simd::sort_f32_total_order(x, order, &ParallelSortConfig::default());
+            return Ok(());
+        }
+    }
+    sort_by(x, &|a, b| float_order.compare(a, b, order))
+}
+
+// 並列化の閾値やスレッドプールを config で指定してソートする
+pub fn sort_by_with_config<T, F>(
+    x: &mut [T],
+    comparator: &F,
+    config: &ParallelSortConfig,
+) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    // 要素数が2のべき乗でなくてもソートできるので、常に Ok を返す
+    // comparator のパニックは捕捉せずに、そのまま呼び出し元に伝える
+    config.install(|| do_sort(x, true, comparator, config, 0));
+    Ok(())
+}
+
+// sort_by_with_config と同じだが、comparator のパニックを捕捉して
+// Err(SortError::ComparatorPanicked) を返す
+pub fn sort_by_catching<T, F>(
+    x: &mut [T],
+    comparator: &F,
+    config: &ParallelSortConfig,
+) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    catching(|| sort_by_with_config(x, comparator, config))
+}
+
+// config の設定でソートし、比較や交換の回数を返す
+// config.stats にカウンタが設定されていても使わず、このソートだけの回数を数える
+pub fn sort_by_with_stats<T, F>(
+    x: &mut [T],
+    comparator: &F,
+    config: &ParallelSortConfig,
+) -> Result<SortStats, SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    let counter = Arc::new(StatsCounter::new());
+    let config = ParallelSortConfig {
+        stats: Some(Arc::clone(&counter)),
+        ..config.clone()
+    };
+    sort_by_with_config(x, comparator, &config)?;
+    Ok(counter.snapshot())
 }
 
-pub fn sort<T: Ord + Send>(x: &mut [T], order: &SortOrder) -> Result<(), String> {
+// 要素の型が u32 か i32 なら、SIMD 命令を使う simd モジュールのソートに切り替える
+// SIMD 版はハイブリッドモードと統計に対応していないので、config.hybrid か config.stats を
+// 指定したときは切り替えない。それ以外の型は comparator でソートする
+pub fn sort_with_config<T: Ord + Send>(
+    x: &mut [T],
+    order: &SortOrder,
+    config: &ParallelSortConfig,
+) -> Result<(), SortError> {
+    if config.hybrid.is_none() && config.stats.is_none() {
+        if let Some(x) = simd::downcast_slice_mut::<T, u32>(x) {
+            simd::sort_with_config(x, order, config);
+            return Ok(());
+        }
+        if let Some(x) = simd::downcast_slice_mut::<T, i32>(x) {
+            simd::sort_with_config(x, order, config);
+            return Ok(());
+        }
+    }
     // do_sort を呼ぶ代わりに、sort_by を呼ぶようにする
-    // is_power_of_two は sort_by が呼ぶので、ここからは削除した
     match *order {
-        SortOrder::Ascending => sort_by(x, &|a, b| a.cmp(b)),
-        SortOrder::Descending => sort_by(x, &|a, b| b.cmp(a)),
+        SortOrder::Ascending => sort_by_with_config(x, &|a, b| a.cmp(b), config),
+        SortOrder::Descending => sort_by_with_config(x, &|a, b| b.cmp(a), config),
+    }
+}
+
+// ParallelSortConfig::default() で使われる閾値
+pub const PARALLEL_THRESHOLD: usize = 4096;
+
+// 並列ソートの設定
+// 同じプロセス内の複数のサービスがソートする場合でも、専用のスレッドプールを
+// 指定すれば rayon のグローバルプールを共有せずに済む
+#[derive(Debug, Clone)]
+pub struct ParallelSortConfig {
+    // do_sort で分割後の要素数がこの値以上なら並列にソートする
+    pub sort_threshold: usize,
+    // sub_sort で分割後の要素数がこの値以上なら並列にマージする
+    pub sub_sort_threshold: usize,
+    // ソートを実行するスレッドプール。None なら rayon のグローバルプールを使う
+    pub thread_pool: Option<Arc<ThreadPool>>,
+    // rayon::join をネストしてよい深さの上限。None なら制限しない
+    pub max_depth: Option<usize>,
+    // ハイブリッドモードの設定。None なら1要素になるまでバイトニックソートで分割する
+    pub hybrid: Option<Hybrid>,
+    // Some なら、比較や交換の回数をこのカウンタに記録する
+    pub stats: Option<Arc<StatsCounter>>,
+}
+
+// ハイブリッドモード
+// バイトニックソートは1要素になるまで分割するので、比較の回数が O(n log² n) になる
+// 要素数が block_size 以下になったブロックは base_case でソートし、
+// それより大きい部分だけをバイトニックマージ（sub_sort）で組み合わせる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hybrid {
+    pub block_size: usize,
+    pub base_case: BaseCase,
+}
+
+// ハイブリッドモードで小さなブロックをソートするアルゴリズム
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaseCase {
+    // 挿入ソート。ブロックが数十要素程度までなら速い
+    InsertionSort,
+    // 標準ライブラリの slice::sort_unstable_by（pattern-defeating quicksort）
+    SortUnstable,
+}
+
+impl BaseCase {
+    // x を forward の順にソートし、比較と交換の回数を返す
+    // SortUnstable の交換の回数は数えられないので 0 を返す
+    fn sort<T, F>(self, x: &mut [T], forward: bool, comparator: &F) -> (usize, usize)
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        match self.try_sort(x, forward, &|a, b| Ok::<_, Infallible>(comparator(a, b))) {
+            Ok(counts) => counts,
+            Err(e) => match e {},
+        }
+    }
+
+    // 失敗するかもしれない comparator でソートする。try_do_sort から呼ばれる
+    // comparator が Err を返したら、それ以降は比較せずに Equal とみなしてソートを終わらせる
+    // 挿入ソートも sort_unstable_by も、Equal が返れば要素を失わずに終わる
+    fn try_sort<T, E, F>(
+        self,
+        x: &mut [T],
+        forward: bool,
+        comparator: &F,
+    ) -> Result<(usize, usize), E>
+    where
+        F: Fn(&T, &T) -> Result<Ordering, E>,
+    {
+        let comparisons = Cell::new(0);
+        let error = Cell::new(None);
+        let failed = Cell::new(false);
+        let compare = |a: &T, b: &T| {
+            if failed.get() {
+                return Ordering::Equal;
+            }
+            comparisons.set(comparisons.get() + 1);
+            let result = if forward {
+                comparator(a, b)
+            } else {
+                comparator(b, a)
+            };
+            result.unwrap_or_else(|e| {
+                error.set(Some(e));
+                failed.set(true);
+                Ordering::Equal
+            })
+        };
+        let mut swaps = 0;
+        match self {
+            BaseCase::InsertionSort => {
+                for i in 1..x.len() {
+                    // x[..i] はソート済み。x[i] を前の要素と交換しながら、入るべき位置まで移動する
+                    let mut j = i;
+                    while j > 0 && compare(&x[j - 1], &x[j]) == Ordering::Greater {
+                        x.swap(j - 1, j);
+                        swaps += 1;
+                        j -= 1;
+                    }
+                }
+            }
+            BaseCase::SortUnstable => x.sort_unstable_by(compare),
+        }
+        match error.into_inner() {
+            Some(e) => Err(e),
+            None => Ok((comparisons.get(), swaps)),
+        }
+    }
+}
+
+impl Default for ParallelSortConfig {
+    fn default() -> Self {
+        Self {
+            sort_threshold: PARALLEL_THRESHOLD,
+            sub_sort_threshold: PARALLEL_THRESHOLD,
+            thread_pool: None,
+            max_depth: None,
+            hybrid: None,
+            stats: None,
+        }
     }
 }
 
-const PARALLEL_THRESHOLD: usize = 4096;
+impl ParallelSortConfig {
+    // 閾値の候補ごとに len 要素の u32 ベクタを実際にソートして時間を計り、
+    // このマシンで最も速かった閾値を設定した config を返す
+    // スレッドプール、max_depth、hybrid、stats は self のものを引き継ぐ
+    // 計測のためのソートは stats のカウンタに記録しない
+    pub fn auto_tune(&self, len: usize) -> Self {
+        const CANDIDATES: [usize; 6] = [256, 1024, 4096, 16384, 65536, 262144];
+
+        let mut config = Self {
+            stats: None,
+            ..self.clone()
+        };
+        // まず両方の閾値を同じ値にして do_sort の閾値を決める
+        config.sort_threshold = fastest(&CANDIDATES, len, |threshold| Self {
+            sort_threshold: threshold,
+            sub_sort_threshold: threshold,
+            ..config.clone()
+        });
+        // 次に do_sort の閾値を固定して sub_sort の閾値を決める
+        config.sub_sort_threshold = fastest(&CANDIDATES, len, |threshold| Self {
+            sub_sort_threshold: threshold,
+            ..config.clone()
+        });
+        config.stats = self.stats.clone();
+        config
+    }
+
+    // 要素数 len のスライスを深さ depth で並列に do_sort するかどうか
+    pub(crate) fn parallel_sort(&self, len: usize, depth: usize) -> bool {
+        len >= self.sort_threshold && self.below_max_depth(depth)
+    }
+
+    // 要素数 len のスライスを深さ depth で並列に sub_sort するかどうか
+    pub(crate) fn parallel_sub_sort(&self, len: usize, depth: usize) -> bool {
+        len >= self.sub_sort_threshold && self.below_max_depth(depth)
+    }
+
+    // 要素数 len のブロックを、分割せずに base_case でソートするなら、その base_case を返す
+    pub(crate) fn base_case(&self, len: usize) -> Option<BaseCase> {
+        match self.hybrid {
+            Some(hybrid) if len <= hybrid.block_size => Some(hybrid.base_case),
+            _ => None,
+        }
+    }
+
+    fn below_max_depth(&self, depth: usize) -> bool {
+        self.max_depth.map_or(true, |max_depth| depth < max_depth)
+    }
+
+    // スレッドプールが指定されていれば、その中で op を実行する
+    pub(crate) fn install<OP, R>(&self, op: OP) -> R
+    where
+        OP: FnOnce() -> R + Send,
+        R: Send,
+    {
+        match self.thread_pool {
+            Some(ref pool) => pool.install(op),
+            None => op(),
+        }
+    }
+}
 
-fn do_sort<T, F>(x: &mut [T], forward: bool, comparator: &F)
+// 候補の閾値のうち、ソートにかかった時間が最も短かったものを返す
+// 計測のぶれを抑えるため、それぞれ3回ソートして最短の時間を使う
+fn fastest<C>(candidates: &[usize], len: usize, config_for: C) -> usize
 where
+    C: Fn(usize) -> ParallelSortConfig,
+{
+    let mut best = (candidates[0], Duration::from_secs(u64::MAX));
+    for &threshold in candidates {
+        let config = config_for(threshold);
+        for _ in 0..3 {
+            let mut x = new_u32_vec(len);
+            let start = Instant::now();
+            // SIMD 版ではなく、comparator を使う汎用の経路で計測する
+            sort_by_with_config(&mut x, &|a, b| a.cmp(b), &config)
+                .expect("comparator never panics");
+            let elapsed = start.elapsed();
+            if elapsed < best.1 {
+                best = (threshold, elapsed);
+            }
+        }
+    }
+    best.0
+}
+
+// 要素数が2のべき乗でない場合の扱いは third と同じく、末尾に仮想的なパディングが
+// あるものとみなす
+// depth は rayon::join をネストした深さ
+fn do_sort<T, F>(
+    x: &mut [T],
+    forward: bool,
+    comparator: &F,
+    config: &ParallelSortConfig,
+    depth: usize,
+) where
     T: Send,
     F: Sync + Fn(&T, &T) -> Ordering,
 {
     // 未実装の意味。コンパイルは通るが、実行すると panic する
     // unimplemented!();
 
+    // ハイブリッドモードでは、小さなブロックをバイトニックソート以外のアルゴリズムでソートする
+    // 前半と後半がそれぞれ逆向きにソートされていれば、sub_sort でマージできる
+    if let Some(base_case) = config.base_case(x.len()) {
+        let (comparisons, swaps) = base_case.sort(x, forward, comparator);
+        if let Some(ref stats) = config.stats {
+            stats.add_comparisons(comparisons);
+            stats.add_swaps(swaps);
+        }
+        return;
+    }
+
     if x.len() > 1 {
         let mid_point = x.len() / 2;
 
@@ -53,8 +933,8 @@ where
         // let second = &mut x[mid_point..];
         let (first, second) = x.split_at_mut(mid_point);
 
-        // xの分割後の要素数を閾値 PARALLEL_THRESHOLD と比較する
-        if mid_point >= PARALLEL_THRESHOLD {
+        // xの分割後の要素数を閾値 config.sort_threshold と比較する
+        if config.parallel_sort(mid_point, depth) {
             // 閾値以上なら並列にソートする（並列処理）
 
             // comparator クロージャの型Fには Sync 境界が必要で、xの要素の型TにはSend境界が必要
@@ -62,45 +942,62 @@ where
             // error[E0277]: `F` cannot be shared between threads safely
             // error[E0277]: `T` cannot be sent between threads safely
 
+            if let Some(ref stats) = config.stats {
+                stats.add_task_split();
+            }
             rayon::join(
-                || do_sort(first, true, comparator),
-                || do_sort(second, false, comparator),
+                || do_sort(first, !forward, comparator, config, depth + 1),
+                || do_sort(second, forward, comparator, config, depth + 1),
             );
         } else {
             // x をバイトニックソートする
-            // 第二引数が true のときは comparator で示される順序でソート
-            do_sort(first, true, comparator);
-            // 第二引数が false のときは comparator とは逆順でソート
-            do_sort(second, false, comparator);
+            // 前半は逆順、後半は forward の順でソートする
+            do_sort(first, !forward, comparator, config, depth);
+            do_sort(second, forward, comparator, config, depth);
         }
 
-        sub_sort(x, forward, comparator);
+        sub_sort(x, forward, comparator, config, depth);
     }
 }
 
-fn sub_sort<T, F>(x: &mut [T], forward: bool, comparator: &F)
-where
+fn sub_sort<T, F>(
+    x: &mut [T],
+    forward: bool,
+    comparator: &F,
+    config: &ParallelSortConfig,
+    depth: usize,
+) where
     T: Send,
     F: Sync + Fn(&T, &T) -> Ordering,
 {
     if x.len() > 1 {
-        compare_and_swap(x, forward, comparator);
-        let mid_point = x.len() / 2;
+        let swaps = compare_and_swap(x, forward, comparator);
+        // x.len() 未満で最大の2のべき乗で分割する
+        let mid_point = x.len().next_power_of_two() / 2;
+        if let Some(ref stats) = config.stats {
+            stats.add_stage();
+            stats.add_comparisons(x.len() - mid_point);
+            stats.add_swaps(swaps);
+        }
         let (first, second) = x.split_at_mut(mid_point);
 
-        if mid_point >= PARALLEL_THRESHOLD {
+        if config.parallel_sub_sort(mid_point, depth) {
+            if let Some(ref stats) = config.stats {
+                stats.add_task_split();
+            }
             rayon::join(
-                || sub_sort(first, forward, comparator),
-                || sub_sort(second, forward, comparator),
+                || sub_sort(first, forward, comparator, config, depth + 1),
+                || sub_sort(second, forward, comparator, config, depth + 1),
             );
         } else {
-            sub_sort(first, forward, comparator);
-            sub_sort(second, forward, comparator);
+            sub_sort(first, forward, comparator, config, depth);
+            sub_sort(second, forward, comparator, config, depth);
         }
     }
 }
 
-fn compare_and_swap<T, F>(x: &mut [T], forward: bool, comparator: &F)
+// 交換した回数を返す
+fn compare_and_swap<T, F>(x: &mut [T], forward: bool, comparator: &F) -> usize
 where
     F: Fn(&T, &T) -> Ordering,
 {
@@ -111,13 +1008,336 @@ where
         Ordering::Less
     };
 
-    let mid_point = x.len() / 2;
+    let mid_point = x.len().next_power_of_two() / 2;
+    let mut swaps = 0;
 
-    for i in 0..mid_point {
+    // mid_point + i が x.len() 以上になる比較相手はパディングなので比較しない
+    for i in 0..(x.len() - mid_point) {
         // comparator クロージャで2要素を比較し、返されたOrderingのバリアントが
         // swap_condition とひとしいなら要素を交換する
         if comparator(&x[i], &x[mid_point + i]) == swap_condition {
-            x.swap(i, mid_point + i)
+            x.swap(i, mid_point + i);
+            swaps += 1;
+        }
+    }
+    swaps
+}
+
+// do_sort、sub_sort、compare_and_swap と同じ再帰で、comparator のエラーを呼び出し元に返す
+// failed はどこかで comparator が Err を返したことを表す。rayon::join のもう片方のタスクは
+// failed を見て処理を打ち切る（そのタスク自身は Ok を返し、エラーは失敗した側が返す）
+// config.hybrid と config.stats は do_sort と同じように扱う
+fn try_do_sort<T, E, F>(
+    x: &mut [T],
+    forward: bool,
+    comparator: &F,
+    config: &ParallelSortConfig,
+    depth: usize,
+    failed: &AtomicBool,
+    progress: Option<&ProgressReporter>,
+) -> Result<(), E>
+where
+    T: Send,
+    E: Send,
+    F: Sync + Fn(&T, &T) -> Result<Ordering, E>,
+{
+    if failed.load(AtomicOrdering::Relaxed) {
+        return Ok(());
+    }
+
+    if let Some(base_case) = config.base_case(x.len()) {
+        let (comparisons, swaps) = base_case
+            .try_sort(x, forward, comparator)
+            .inspect_err(|_| failed.store(true, AtomicOrdering::Relaxed))?;
+        if let Some(ref stats) = config.stats {
+            stats.add_comparisons(comparisons);
+            stats.add_swaps(swaps);
+        }
+        if let Some(progress) = progress {
+            if x.len() > 1 {
+                progress.step();
+            }
+        }
+        return Ok(());
+    }
+
+    if x.len() > 1 {
+        let mid_point = x.len() / 2;
+        let (first, second) = x.split_at_mut(mid_point);
+
+        if config.parallel_sort(mid_point, depth) {
+            if let Some(ref stats) = config.stats {
+                stats.add_task_split();
+            }
+            let (first, second) = rayon::join(
+                || {
+                    try_do_sort(
+                        first,
+                        !forward,
+                        comparator,
+                        config,
+                        depth + 1,
+                        failed,
+                        progress,
+                    )
+                },
+                || {
+                    try_do_sort(
+                        second,
+                        forward,
+                        comparator,
+                        config,
+                        depth + 1,
+                        failed,
+                        progress,
+                    )
+                },
+            );
+            first.and(second)?;
+        } else {
+            try_do_sort(first, !forward, comparator, config, depth, failed, progress)?;
+            try_do_sort(second, forward, comparator, config, depth, failed, progress)?;
+        }
+
+        let merge = progress.map(|progress| MergeProgress::new(progress, x.len()));
+        try_sub_sort(
+            x,
+            forward,
+            comparator,
+            config,
+            depth,
+            failed,
+            merge.as_ref(),
+        )?;
+    }
+    Ok(())
+}
+
+fn try_sub_sort<T, E, F>(
+    x: &mut [T],
+    forward: bool,
+    comparator: &F,
+    config: &ParallelSortConfig,
+    depth: usize,
+    failed: &AtomicBool,
+    merge: Option<&MergeProgress>,
+) -> Result<(), E>
+where
+    T: Send,
+    E: Send,
+    F: Sync + Fn(&T, &T) -> Result<Ordering, E>,
+{
+    if x.len() > 1 && !failed.load(AtomicOrdering::Relaxed) {
+        let (comparisons, swaps) = try_compare_and_swap(x, forward, comparator, failed)?;
+        let mid_point = x.len().next_power_of_two() / 2;
+        if let Some(ref stats) = config.stats {
+            stats.add_stage();
+            stats.add_comparisons(comparisons);
+            stats.add_swaps(swaps);
+        }
+        // ほかのスレッドが失敗していると、比較を途中でやめて戻ってくることがある
+        if let Some(merge) = merge {
+            if !failed.load(AtomicOrdering::Relaxed) {
+                merge.compared(mid_point.trailing_zeros() as usize);
+            }
+        }
+        let (first, second) = x.split_at_mut(mid_point);
+
+        if config.parallel_sub_sort(mid_point, depth) {
+            if let Some(ref stats) = config.stats {
+                stats.add_task_split();
+            }
+            let (first, second) = rayon::join(
+                || try_sub_sort(first, forward, comparator, config, depth + 1, failed, merge),
+                || {
+                    try_sub_sort(
+                        second,
+                        forward,
+                        comparator,
+                        config,
+                        depth + 1,
+                        failed,
+                        merge,
+                    )
+                },
+            );
+            first.and(second)?;
+        } else {
+            try_sub_sort(first, forward, comparator, config, depth, failed, merge)?;
+            try_sub_sort(second, forward, comparator, config, depth, failed, merge)?;
+        }
+    }
+    Ok(())
+}
+
+// 比較した回数と交換した回数を返す
+fn try_compare_and_swap<T, E, F>(
+    x: &mut [T],
+    forward: bool,
+    comparator: &F,
+    failed: &AtomicBool,
+) -> Result<(usize, usize), E>
+where
+    F: Fn(&T, &T) -> Result<Ordering, E>,
+{
+    let swap_condition = if forward {
+        Ordering::Greater
+    } else {
+        Ordering::Less
+    };
+    let mid_point = x.len().next_power_of_two() / 2;
+    let (mut comparisons, mut swaps) = (0, 0);
+    for i in 0..(x.len() - mid_point) {
+        // ほかのスレッドが失敗していたら、残りの比較をやめる
+        if failed.load(AtomicOrdering::Relaxed) {
+            break;
+        }
+        comparisons += 1;
+        match comparator(&x[i], &x[mid_point + i]) {
+            Ok(ordering) if ordering == swap_condition => {
+                x.swap(i, mid_point + i);
+                swaps += 1;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                failed.store(true, AtomicOrdering::Relaxed);
+                return Err(e);
+            }
+        }
+    }
+    Ok((comparisons, swaps))
+}
+
+// third::do_partial_sort の並列版
+// 先頭から順に絞り込む代わりに、トーナメントのように隣り合うブロックの組を並列に絞り込む
+fn do_partial_sort<T, F>(x: &mut [T], k: usize, comparator: &F, config: &ParallelSortConfig)
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    if k == 0 {
+        return;
+    }
+    if k >= x.len() {
+        do_sort(x, true, comparator, config, 0);
+        return;
+    }
+
+    // ブロックが小さいときは、ひとつのタスクがおよそ sort_threshold 要素を受け持つようにまとめる
+    let min_blocks = (config.sort_threshold / k).max(1);
+    x.par_chunks_mut(k)
+        .with_min_len(min_blocks)
+        .for_each(|block| do_sort(block, false, comparator, config, 0));
+
+    // step は絞り込み済みのブロックの間隔。2 * step 要素ごとに、先頭のブロックと
+    // step 要素先のブロックを組にして、先頭のブロックに小さい方の k 個を集める
+    let mut step = k;
+    while step < x.len() {
+        let min_pairs = (config.sort_threshold / (2 * step)).max(1);
+        x.par_chunks_mut(2 * step)
+            .with_min_len(min_pairs)
+            .for_each(|chunk| {
+                if chunk.len() > step {
+                    let (first, second) = chunk.split_at_mut(step);
+                    let block_len = k.min(second.len());
+                    let block = &mut second[..block_len];
+                    // 後半のブロックも降順なので、昇順に直してから組み合わせる
+                    block.reverse();
+                    prune(&mut first[..k], block, comparator, config);
+                }
+            });
+        step *= 2;
+    }
+    x[..k].reverse();
+}
+
+// third::prune と同じ。降順の best と昇順の block から小さい方の best.len() 個を選び、
+// 降順にソートして best に置く
+fn prune<T, F>(best: &mut [T], block: &mut [T], comparator: &F, config: &ParallelSortConfig)
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    for (a, b) in best.iter_mut().zip(block.iter_mut()) {
+        if comparator(a, b) == Ordering::Greater {
+            mem::swap(a, b);
+        }
+    }
+    sub_sort(best, false, comparator, config, 0);
+}
+
+// do_sort、sub_sort、compare_and_swap と同じ再帰で、キーと値を一緒に並べ替える
+fn do_sort_pairs<K, V>(
+    keys: &mut [K],
+    values: &mut [V],
+    forward: bool,
+    config: &ParallelSortConfig,
+    depth: usize,
+) where
+    K: Ord + Send,
+    V: Send,
+{
+    if keys.len() > 1 {
+        let mid_point = keys.len() / 2;
+        let (first_keys, second_keys) = keys.split_at_mut(mid_point);
+        let (first_values, second_values) = values.split_at_mut(mid_point);
+
+        if config.parallel_sort(mid_point, depth) {
+            rayon::join(
+                || do_sort_pairs(first_keys, first_values, !forward, config, depth + 1),
+                || do_sort_pairs(second_keys, second_values, forward, config, depth + 1),
+            );
+        } else {
+            do_sort_pairs(first_keys, first_values, !forward, config, depth);
+            do_sort_pairs(second_keys, second_values, forward, config, depth);
+        }
+
+        sub_sort_pairs(keys, values, forward, config, depth);
+    }
+}
+
+fn sub_sort_pairs<K, V>(
+    keys: &mut [K],
+    values: &mut [V],
+    forward: bool,
+    config: &ParallelSortConfig,
+    depth: usize,
+) where
+    K: Ord + Send,
+    V: Send,
+{
+    if keys.len() > 1 {
+        compare_and_swap_pairs(keys, values, forward);
+        let mid_point = keys.len().next_power_of_two() / 2;
+        let (first_keys, second_keys) = keys.split_at_mut(mid_point);
+        let (first_values, second_values) = values.split_at_mut(mid_point);
+
+        if config.parallel_sub_sort(mid_point, depth) {
+            rayon::join(
+                || sub_sort_pairs(first_keys, first_values, forward, config, depth + 1),
+                || sub_sort_pairs(second_keys, second_values, forward, config, depth + 1),
+            );
+        } else {
+            sub_sort_pairs(first_keys, first_values, forward, config, depth);
+            sub_sort_pairs(second_keys, second_values, forward, config, depth);
+        }
+    }
+}
+
+fn compare_and_swap_pairs<K: Ord, V>(keys: &mut [K], values: &mut [V], forward: bool) {
+    let swap_condition = if forward {
+        Ordering::Greater
+    } else {
+        Ordering::Less
+    };
+
+    let mid_point = keys.len().next_power_of_two() / 2;
+
+    for i in 0..(keys.len() - mid_point) {
+        if keys[i].cmp(&keys[mid_point + i]) == swap_condition {
+            // キーと値で同じ交換をする
+            keys.swap(i, mid_point + i);
+            values.swap(i, mid_point + i);
         }
     }
 }
@@ -125,9 +1345,27 @@ where
 // このモジュールは cargo test を実行したときのみコンパイルされる
 #[cfg(test)]
 mod tests {
-    use super::{sort, sort_by};
-    use crate::utils::{is_sorted_ascending, is_sorted_descending, new_u32_vec};
+    use super::{
+        argsort, argsort_by, argsort_by_with_config, bitonic_merge, bitonic_merge_by,
+        bitonic_merge_by_with_config, merge_sorted, partial_sort, partial_sort_by_with_config,
+        sort, sort_batch, sort_batch_by, sort_batch_by_with_config, sort_by, sort_by_cached_key,
+        sort_by_cancellable, sort_by_catching, sort_by_key, sort_by_stable,
+        sort_by_stable_with_config, sort_by_with_config, sort_by_with_stats, sort_cancellable,
+        sort_floats, sort_pairs, sort_pairs_with_config, sort_segments, sort_segments_by,
+        sort_segments_by_with_config, sort_with_config, top_k, try_sort_by,
+        try_sort_by_with_config, BaseCase, Hybrid, ParallelSortConfig, Progress,
+    };
+    use crate::float::{FloatOrder, NanPlacement, ZeroOrder};
+    use crate::stats::{SortStats, StatsCounter};
+    use crate::utils::{apply_permutation, is_sorted_ascending, is_sorted_descending, new_u32_vec};
+    use crate::SortError;
     use crate::SortOrder::*;
+    use std::cmp::Ordering;
+    use std::convert::TryInto;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
 
     // impl PartialEq for Student {
     //     fn eq(&self, other: &Self) -> bool{
@@ -213,6 +1451,152 @@ mod tests {
         assert_eq!(x, expected);
     }
 
+    #[test]
+    fn sort_students_stable_by_age_then_name() {
+        // 同じ年齢の生徒がいるテストデータ
+        let taro = Student::new("Taro", "Yamada", 16);
+        let hanako = Student::new("Hanako", "Yamada", 14);
+        let kyoko = Student::new("Kyoko", "Ito", 15);
+        let ryosuke = Student::new("Ryosuke", "Hayashi", 17);
+        let jiro = Student::new("Jiro", "Suzuki", 16);
+        let akiko = Student::new("Akiko", "Sato", 14);
+
+        let mut x = vec![&taro, &hanako, &kyoko, &ryosuke, &jiro, &akiko];
+
+        // まず副キーの名前でソートし、次に主キーの年齢で安定ソートする
+        // 同じ年齢の生徒は名前の順に並んだままになる
+        assert_eq!(
+            sort_by_stable(&mut x, &|a, b| a.first_name.cmp(&b.first_name)),
+            Ok(())
+        );
+        assert_eq!(sort_by_stable(&mut x, &|a, b| a.age.cmp(&b.age)), Ok(()));
+
+        let expected = vec![&akiko, &hanako, &kyoko, &jiro, &taro, &ryosuke];
+        assert_eq!(x, expected);
+    }
+
+    #[test]
+    fn sort_stable_keeps_original_order() {
+        // (キー, 元の位置) の組を作り、キーだけで安定ソートする
+        let mut x: Vec<(u32, usize)> = new_u32_vec(5000)
+            .into_iter()
+            .map(|n| n % 10)
+            .enumerate()
+            .map(|(i, n)| (n, i))
+            .collect();
+        assert_eq!(sort_by_stable(&mut x, &|a, b| a.0.cmp(&b.0)), Ok(()));
+        // キーが同じなら元の位置の昇順になっている
+        assert!(is_sorted_ascending(&x));
+    }
+
+    #[test]
+    fn sort_students_by_key() {
+        let taro = Student::new("Taro", "Yamada", 16);
+        let hanako = Student::new("Hanako", "Yamada", 14);
+        let kyoko = Student::new("Kyoko", "Ito", 15);
+        let ryosuke = Student::new("Ryosuke", "Hayashi", 17);
+
+        let mut x = vec![&taro, &hanako, &kyoko, &ryosuke];
+        assert_eq!(sort_by_key(&mut x, &|s| s.age), Ok(()));
+        assert_eq!(x, vec![&hanako, &kyoko, &taro, &ryosuke]);
+    }
+
+    #[test]
+    fn sort_str_by_cached_key() {
+        // キーを計算した回数を数える
+        let calls = AtomicUsize::new(0);
+        let mut x = vec![
+            "rust",
+            "Is",
+            "fast",
+            "AND",
+            "Memory-efficient",
+            "with",
+            "no",
+            "gc",
+        ];
+        assert_eq!(
+            sort_by_cached_key(&mut x, &|s| {
+                calls.fetch_add(1, AtomicOrdering::Relaxed);
+                s.to_lowercase()
+            }),
+            Ok(())
+        );
+        assert_eq!(
+            x,
+            vec![
+                "AND",
+                "fast",
+                "gc",
+                "Is",
+                "Memory-efficient",
+                "no",
+                "rust",
+                "with"
+            ]
+        );
+        // キーは要素ごとに一度だけ計算される
+        assert_eq!(calls.load(AtomicOrdering::Relaxed), x.len());
+    }
+
+    #[test]
+    fn argsort_columns() {
+        // 列指向のデータ。ages をキーにして、ほかの列も同じ順序に並べ替える
+        let mut ages: Vec<u8> = vec![16, 14, 15, 17, 14];
+        let mut names = vec!["Taro", "Hanako", "Kyoko", "Ryosuke", "Akiko"];
+        let mut ids: Vec<u32> = vec![1, 2, 3, 4, 5];
+
+        let perm = argsort(&ages, &Ascending).unwrap();
+        // 同じ年齢（14歳）の Hanako と Akiko は元の順序のまま
+        assert_eq!(perm, vec![1, 4, 2, 0, 3]);
+
+        apply_permutation(&mut ages, &perm);
+        apply_permutation(&mut names, &perm);
+        apply_permutation(&mut ids, &perm);
+        assert_eq!(ages, vec![14, 14, 15, 16, 17]);
+        assert_eq!(names, vec!["Hanako", "Akiko", "Kyoko", "Taro", "Ryosuke"]);
+        assert_eq!(ids, vec![2, 5, 3, 1, 4]);
+    }
+
+    #[test]
+    fn argsort_by_does_not_move_data() {
+        let x = new_u32_vec(10_000);
+        let perm = argsort_by(&x, &|a, b| b.cmp(a)).unwrap();
+        let sorted: Vec<u32> = perm.iter().map(|&i| x[i]).collect();
+        assert!(is_sorted_descending(&sorted));
+    }
+
+    #[test]
+    fn sort_pairs_moves_values_with_keys() {
+        let mut keys: Vec<u32> = vec![16, 14, 15, 17, 13];
+        let mut values = vec!["Taro", "Hanako", "Kyoko", "Ryosuke", "Akiko"];
+        assert_eq!(sort_pairs(&mut keys, &mut values, &Descending), Ok(()));
+        assert_eq!(keys, vec![17, 16, 15, 14, 13]);
+        assert_eq!(values, vec!["Ryosuke", "Taro", "Kyoko", "Hanako", "Akiko"]);
+    }
+
+    #[test]
+    fn sort_pairs_large() {
+        let mut keys = new_u32_vec(20_000);
+        // 値にはキーから計算できるものを入れておき、一緒に移動したことを確認する
+        let mut values: Vec<u64> = keys.iter().map(|&k| k as u64 * 2).collect();
+        assert_eq!(sort_pairs(&mut keys, &mut values, &Ascending), Ok(()));
+        assert!(is_sorted_ascending(&keys));
+        assert!(keys.iter().zip(&values).all(|(&k, &v)| v == k as u64 * 2));
+    }
+
+    #[test]
+    fn sort_pairs_length_mismatch() {
+        let mut keys: Vec<u32> = vec![3, 1, 2];
+        let mut values = vec!["c", "a"];
+        assert_eq!(
+            sort_pairs(&mut keys, &mut values, &Ascending),
+            Err(SortError::LengthMismatch { keys: 3, values: 2 })
+        );
+        // エラーのときは何も並べ替えない
+        assert_eq!(keys, vec![3, 1, 2]);
+    }
+
     // #[test] のついた関数はcargo testしたときに実行される
     #[test]
     fn sort_32_ascending() {
@@ -293,9 +1677,832 @@ mod tests {
     }
 
     #[test]
-    fn sort_to_fail() {
+    fn merge_sorted_large() {
+        let mut a = new_u32_vec(30_000);
+        let mut b = new_u32_vec(12_345);
+        sort(&mut a, &Descending).unwrap();
+        sort(&mut b, &Descending).unwrap();
+        let mut out = vec![0; a.len() + b.len()];
+        assert_eq!(merge_sorted(&a, &b, &mut out, &Descending), Ok(()));
+        assert!(is_sorted_descending(&out));
+
+        let mut out = vec![0; 3];
+        assert_eq!(
+            merge_sorted(&a, &b, &mut out, &Descending),
+            Err(SortError::OutputLengthMismatch {
+                expected: 42_345,
+                actual: 3
+            })
+        );
+    }
+
+    #[test]
+    fn bitonic_merge_matches_third() {
+        for &(len, mid) in &[
+            (0, 0),
+            (5, 2),
+            (100, 0),
+            (100, 100),
+            (20_000, 8_192),
+            (20_000, 777),
+        ] {
+            let mut x = new_u32_vec(len);
+            x[..mid].sort();
+            x[mid..].sort();
+            let mut expected = x.clone();
+            crate::third::bitonic_merge(&mut expected, mid, &Ascending).unwrap();
+            assert_eq!(bitonic_merge(&mut x, mid, &Ascending), Ok(()));
+            assert!(is_sorted_ascending(&x));
+            assert_eq!(x, expected);
+        }
+
+        let mut x = vec![14, 11, 10, 30, 20, 4];
+        assert_eq!(bitonic_merge_by(&mut x, 3, &|a, b| b.cmp(a)), Ok(()));
+        assert_eq!(x, vec![30, 20, 14, 11, 10, 4]);
+        assert_eq!(
+            bitonic_merge_by(&mut x, 7, &|a, b| b.cmp(a)),
+            Err(SortError::MidOutOfRange { mid: 7, len: 6 })
+        );
+    }
+
+    #[test]
+    fn top_k_matches_third() {
+        // ブロックの数が2のべき乗でない場合や、最後のブロックが短い場合も含める
+        for &(len, k) in &[
+            (8, 3),
+            (100, 7),
+            (50_000, 1),
+            (50_000, 100),
+            (50_000, 4_096),
+            (50_000, 30_000),
+            (65_536, 1_000),
+        ] {
+            let x = new_u32_vec(len);
+            for order in &[Ascending, Descending] {
+                let selected = top_k(&x, k, order).unwrap();
+                assert_eq!(selected, crate::third::top_k(&x, k, order).unwrap());
+
+                let mut expected = x.clone();
+                crate::third::sort(&mut expected, order).unwrap();
+                assert_eq!(selected[..], expected[..k], "len: {}, k: {}", len, k);
+            }
+        }
+    }
+
+    #[test]
+    fn partial_sort_keeps_elements() {
+        let mut x = new_u32_vec(20_000);
+        assert_eq!(partial_sort(&mut x, 333, &Descending), Ok(()));
+        assert!(is_sorted_descending(&x[..333]));
+        // 選ばれなかった要素はどれも、選ばれた要素以下
+        assert!(x[333..].iter().all(|v| *v <= x[332]));
+        x.sort();
+        let mut expected = new_u32_vec(20_000);
+        expected.sort();
+        assert_eq!(x, expected);
+    }
+
+    #[test]
+    fn sort_batch_rows() {
+        for &row_len in &[1, 16, 100, 256] {
+            let mut data = new_u32_vec(row_len * 1000);
+            let mut expected = data.clone();
+            assert_eq!(sort_batch(&mut data, row_len, &Descending), Ok(()));
+            for row in expected.chunks_mut(row_len) {
+                crate::third::sort(row, &Descending).unwrap();
+            }
+            assert_eq!(data, expected);
+        }
+
+        let mut data = vec!["c", "a", "b", "z", "x", "y"];
+        assert_eq!(sort_batch_by(&mut data, 3, &|a, b| a.cmp(b)), Ok(()));
+        assert_eq!(data, vec!["a", "b", "c", "x", "y", "z"]);
+    }
+
+    #[test]
+    fn sort_batch_row_length_mismatch() {
+        let mut data = vec![3, 2, 1, 6, 5];
+        assert_eq!(
+            sort_batch(&mut data, 2, &Ascending),
+            Err(SortError::RowLengthMismatch { len: 5, row_len: 2 })
+        );
+        assert_eq!(
+            sort_batch(&mut data, 0, &Ascending),
+            Err(SortError::RowLengthMismatch { len: 5, row_len: 0 })
+        );
+        // エラーのときは何も並べ替えない
+        assert_eq!(data, vec![3, 2, 1, 6, 5]);
+        assert_eq!(sort_batch(&mut Vec::<u32>::new(), 0, &Ascending), Ok(()));
+    }
+
+    #[test]
+    fn sort_segments_mixed_lengths() {
+        // 空のセグメント、2のべき乗でない長さ、並列化の閾値を超える長さを混ぜる
+        let lengths = [0, 1, 3, 100, 0, 20_000, 17, 4_096, 5, 999];
+        let mut offsets = vec![0];
+        for len in &lengths {
+            offsets.push(offsets.last().unwrap() + len);
+        }
+        let orders: Vec<_> = (0..lengths.len())
+            .map(|i| if i % 3 == 0 { Descending } else { Ascending })
+            .collect();
+
+        let mut data = new_u32_vec(*offsets.last().unwrap());
+        let mut expected = data.clone();
+        assert_eq!(sort_segments(&mut data, &offsets, &orders), Ok(()));
+
+        for (bounds, order) in offsets.windows(2).zip(&orders) {
+            crate::third::sort(&mut expected[bounds[0]..bounds[1]], order).unwrap();
+        }
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn sort_segments_by_length() {
+        let mut data = vec![
+            "Rust",
+            "is",
+            "fast",
+            "and",
+            "memory-efficient",
+            "with",
+            "no",
+            "GC",
+        ];
+        let offsets = [0, 3, 8];
+        assert_eq!(
+            sort_segments_by(&mut data, &offsets, &[Ascending, Descending], &|a, b| a
+                .len()
+                .cmp(&b.len())),
+            Ok(())
+        );
+        assert_eq!(data[..3], ["is", "Rust", "fast"]);
+        assert_eq!(data[3..6], ["memory-efficient", "with", "and"]);
+    }
+
+    #[test]
+    fn sort_segments_invalid_offsets() {
+        let mut data = vec![3, 2, 1, 6, 5];
+        assert_eq!(
+            sort_segments(&mut data, &[], &[]),
+            Err(SortError::InvalidOffsets { index: 0 })
+        );
+        assert_eq!(
+            sort_segments(&mut data, &[1, 5], &[Ascending]),
+            Err(SortError::InvalidOffsets { index: 0 })
+        );
+        assert_eq!(
+            sort_segments(&mut data, &[0, 3, 4], &[Ascending, Ascending]),
+            Err(SortError::InvalidOffsets { index: 2 })
+        );
+        assert_eq!(
+            sort_segments(&mut data, &[0, 3, 2, 5], &[Ascending; 3]),
+            Err(SortError::InvalidOffsets { index: 2 })
+        );
+        assert_eq!(
+            sort_segments(&mut data, &[0, 3, 5], &[Ascending]),
+            Err(SortError::SegmentCountMismatch {
+                segments: 2,
+                orders: 1
+            })
+        );
+        // エラーのときは何も並べ替えない
+        assert_eq!(data, vec![3, 2, 1, 6, 5]);
+    }
+
+    #[test]
+    fn sort_floats_matches_third() {
+        // 乱数から NaN、±0.0、±∞ を含む f64 のベクタを作る
+        let x: Vec<f64> = new_u32_vec(30_000)
+            .into_iter()
+            .map(|n| match n % 16 {
+                0 => f64::NAN,
+                1 => -f64::NAN,
+                2 => 0.0,
+                3 => -0.0,
+                4 => f64::INFINITY,
+                _ => (n as f64 - u32::MAX as f64 / 2.0) / 1e6,
+            })
+            .collect();
+
+        for &nan in &[
+            NanPlacement::TotalOrder,
+            NanPlacement::First,
+            NanPlacement::Last,
+        ] {
+            for order in &[Ascending, Descending] {
+                let float_order = FloatOrder {
+                    nan,
+                    zero: ZeroOrder::NegativeFirst,
+                };
+                let mut actual = x.clone();
+                assert_eq!(sort_floats(&mut actual, order, &float_order), Ok(()));
+                let mut expected = x.clone();
+                crate::third::sort_floats(&mut expected, order, &float_order).unwrap();
+                // fourth は third と同じネットワークでソートするので、NaN の符号まで一致する
+                // NaN は == で比べられないので、ビット列で比べる
+                let bits = |v: &[f64]| v.iter().map(|f| f.to_bits()).collect::<Vec<_>>();
+                assert_eq!(bits(&actual), bits(&expected));
+            }
+        }
+    }
+
+    #[test]
+    fn try_sort_by_large() {
+        let mut x = new_u32_vec(50_000);
+        let result: Result<(), String> = try_sort_by(&mut x, &|a, b| Ok(b.cmp(a)));
+        assert_eq!(result, Ok(()));
+        assert!(is_sorted_descending(&x));
+
+        // ひとつの要素だけ比較に失敗する
+        // 既定のプールでは、打ち切りが伝わるまでに走る比較の数がタイミングで変わるので、
+        // エラーが返ることと、x が入力の並べ替えのままであることだけを確かめる
+        let mut x = new_u32_vec(50_000);
+        let bad = x[12_345];
+        let result = try_sort_by(&mut x, &|a, b| {
+            if *a == bad || *b == bad {
+                return Err(format!("cannot compare {}", bad));
+            }
+            Ok(a.cmp(b))
+        });
+        assert_eq!(result, Err(format!("cannot compare {}", bad)));
+        x.sort();
+        let mut expected = new_u32_vec(50_000);
+        expected.sort();
+        assert_eq!(x, expected);
+
+        // 1スレッドのプールなら、最初のエラーのあとは comparator が一度も呼ばれない
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let config = ParallelSortConfig {
+            sort_threshold: 1024,
+            sub_sort_threshold: 1024,
+            thread_pool: Some(Arc::new(pool)),
+            ..Default::default()
+        };
+        let mut x = new_u32_vec(50_000);
+        let failed = AtomicUsize::new(0);
+        let calls_after_error = AtomicUsize::new(0);
+        let result = try_sort_by_with_config(
+            &mut x,
+            &|a, b| {
+                if failed.load(AtomicOrdering::Relaxed) > 0 {
+                    calls_after_error.fetch_add(1, AtomicOrdering::Relaxed);
+                }
+                if *a == bad || *b == bad {
+                    failed.fetch_add(1, AtomicOrdering::Relaxed);
+                    return Err(format!("cannot compare {}", bad));
+                }
+                Ok(a.cmp(b))
+            },
+            &config,
+        );
+        assert_eq!(result, Err(format!("cannot compare {}", bad)));
+        assert_eq!(failed.load(AtomicOrdering::Relaxed), 1);
+        assert_eq!(calls_after_error.load(AtomicOrdering::Relaxed), 0);
+        x.sort();
+        assert_eq!(x, expected);
+    }
+
+    #[test]
+    fn try_sort_by_hybrid() {
+        // base_case の中で comparator が失敗しても、エラーを返して x は並べ替えのまま
+        for &base_case in &[BaseCase::InsertionSort, BaseCase::SortUnstable] {
+            let config = ParallelSortConfig {
+                hybrid: Some(Hybrid {
+                    block_size: 32,
+                    base_case,
+                }),
+                ..Default::default()
+            };
+            let mut x = new_u32_vec(1_000);
+            let result: Result<(), ()> =
+                try_sort_by_with_config(&mut x, &|a, b| Ok(a.cmp(b)), &config);
+            assert_eq!(result, Ok(()));
+            assert!(is_sorted_ascending(&x));
+
+            let mut x = new_u32_vec(1_000);
+            let bad = x[500];
+            let result = try_sort_by_with_config(
+                &mut x,
+                &|a, b| {
+                    if *a == bad || *b == bad {
+                        Err(bad)
+                    } else {
+                        Ok(a.cmp(b))
+                    }
+                },
+                &config,
+            );
+            assert_eq!(result, Err(bad));
+            x.sort();
+            let mut expected = new_u32_vec(1_000);
+            expected.sort();
+            assert_eq!(x, expected);
+        }
+    }
+
+    // パニックの注入に使う。panic_at 回目の比較でパニックする
+    struct Injector {
+        calls: AtomicUsize,
+        panic_at: usize,
+    }
+
+    impl Injector {
+        fn new(panic_at: usize) -> Self {
+            Self {
+                calls: AtomicUsize::new(0),
+                panic_at,
+            }
+        }
+    }
+
+    // 解放された回数を要素ごとに数える型。比較は Injector を通す
+    struct Tracked<'a> {
+        value: u32,
+        id: usize,
+        drops: &'a [AtomicUsize],
+        injector: &'a Injector,
+    }
+
+    impl Drop for Tracked<'_> {
+        fn drop(&mut self) {
+            self.drops[self.id].fetch_add(1, AtomicOrdering::SeqCst);
+        }
+    }
+
+    impl PartialEq for Tracked<'_> {
+        fn eq(&self, other: &Self) -> bool {
+            self.cmp(other) == Ordering::Equal
+        }
+    }
+
+    impl Eq for Tracked<'_> {}
+
+    impl PartialOrd for Tracked<'_> {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Tracked<'_> {
+        fn cmp(&self, other: &Self) -> Ordering {
+            let calls = self.injector.calls.fetch_add(1, AtomicOrdering::SeqCst);
+            if calls == self.injector.panic_at {
+                panic!("injected panic");
+            }
+            self.value.cmp(&other.value)
+        }
+    }
+
+    // まずパニックさせずにソートして比較の回数を数え、次にその範囲のランダムな回数目の
+    // 比較でパニックさせる。伝わってきたパニックを crate::catching で Err にして、元のメッセージの
+    // ままであることを確かめる。パニックしたあとも、スライスが元の要素の並べ替えであること、
+    // 要素がちょうど1回ずつ解放されること（リークも二重解放もないこと）を確かめる
+    fn inject_panics<S>(len: usize, sort: S)
+    where
+        S: Fn(&mut [Tracked]) -> Result<(), SortError>,
+    {
+        fn make<'a>(
+            len: usize,
+            drops: &'a [AtomicUsize],
+            injector: &'a Injector,
+        ) -> Vec<Tracked<'a>> {
+            new_u32_vec(len)
+                .into_iter()
+                .enumerate()
+                .map(|(id, value)| Tracked {
+                    value,
+                    id,
+                    drops,
+                    injector,
+                })
+                .collect()
+        }
+
+        let drops: Vec<AtomicUsize> = (0..len).map(|_| AtomicUsize::new(0)).collect();
+        let injector = Injector::new(usize::MAX);
+        let mut x = make(len, &drops, &injector);
+        assert_eq!(sort(&mut x), Ok(()));
+        drop(x);
+        let total = injector.calls.load(AtomicOrdering::SeqCst);
+        assert!(total > 0);
+
+        for seed in new_u32_vec(16) {
+            let drops: Vec<AtomicUsize> = (0..len).map(|_| AtomicUsize::new(0)).collect();
+            let injector = Injector::new(seed as usize % total);
+            let mut x = make(len, &drops, &injector);
+
+            assert_eq!(
+                crate::catching(|| sort(&mut x)),
+                Err(SortError::ComparatorPanicked("injected panic".to_string()))
+            );
+            // まだどの要素も解放されていない
+            assert!(drops.iter().all(|d| d.load(AtomicOrdering::SeqCst) == 0));
+            // 元の要素の並べ替えになっている
+            let mut ids: Vec<usize> = x.iter().map(|t| t.id).collect();
+            ids.sort();
+            assert!(ids.iter().copied().eq(0..len));
+
+            drop(x);
+            assert!(drops.iter().all(|d| d.load(AtomicOrdering::SeqCst) == 1));
+        }
+    }
+
+    #[test]
+    fn panic_safety_sort() {
+        inject_panics(5_000, |x| sort_by(x, &|a, b| a.cmp(b)));
+        // 並列化の閾値を下げて、rayon::join の中でパニックさせる
+        let config = ParallelSortConfig {
+            sort_threshold: 16,
+            sub_sort_threshold: 16,
+            ..Default::default()
+        };
+        inject_panics(5_000, |x| sort_by_with_config(x, &|a, b| b.cmp(a), &config));
+        let config = ParallelSortConfig {
+            sort_threshold: 64,
+            hybrid: Some(Hybrid {
+                block_size: 20,
+                base_case: BaseCase::InsertionSort,
+            }),
+            ..Default::default()
+        };
+        inject_panics(5_000, |x| sort_by_with_config(x, &|a, b| b.cmp(a), &config));
+    }
+
+    #[test]
+    fn panic_safety_other_sorts() {
+        inject_panics(5_000, |x| sort_by_stable(x, &|a, b| a.cmp(b)));
+        inject_panics(5_000, |x| partial_sort(x, 100, &Ascending));
+        inject_panics(5_000, |x| sort_batch(x, 50, &Descending));
+        inject_panics(5_000, |x| {
+            sort_segments(
+                x,
+                &[0, 10, 4_000, 5_000],
+                &[Ascending, Descending, Ascending],
+            )
+        });
+        // 前半をキー、後半を値として一緒に並べ替える
+        inject_panics(5_000, |x| {
+            let (keys, values) = x.split_at_mut(2_500);
+            sort_pairs(keys, values, &Ascending)
+        });
+        inject_panics(5_000, |x| crate::third::sort(x, &Ascending));
+        inject_panics(5_000, |x| crate::odd_even::par_sort(x, &Ascending));
+        inject_panics(5_000, |x| crate::iterative::par_sort(x, &Ascending));
+
+        // array::sort_array は、展開したネットワーク（N = 32）と、third に任せる N（100）の両方を確かめる
+        inject_panics(32, |x| {
+            crate::array::sort_array::<_, 32>(x.try_into().unwrap(), &Ascending);
+            Ok(())
+        });
+        inject_panics(100, |x| {
+            crate::array::sort_array::<_, 100>(x.try_into().unwrap(), &Ascending);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn panic_safety_propagated() {
+        // try_sort_by はパニックを捕捉せず、そのまま呼び出し元に伝える
+        inject_panics(5_000, |x| {
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                try_sort_by(x, &|a, b| Ok::<_, ()>(a.cmp(b)))
+            }))
+            .map(|result| result.expect("comparator never returns Err"))
+            .map_err(|_| SortError::ComparatorPanicked("injected panic".to_string()))
+        });
+    }
+
+    #[test]
+    fn progress_per_step() {
+        // 要素数 len を config でソートしたときの報告を、届いた順に返す
+        fn reports(len: usize, config: &ParallelSortConfig) -> Vec<Progress> {
+            let reports = Mutex::new(Vec::new());
+            let cancel = AtomicBool::new(false);
+            let progress = |p: Progress| reports.lock().unwrap().push(p);
+            let mut x = new_u32_vec(len);
+            assert_eq!(
+                sort_cancellable(&mut x, &Descending, config, &cancel, Some(&progress)),
+                Ok(())
+            );
+            assert!(is_sorted_descending(&x));
+            reports.into_inner().unwrap()
+        }
+
+        // 8要素なら、8要素のマージが3段、4要素のマージ2つが2段ずつ、2要素のマージ4つが1段ずつ
+        let reports_8 = reports(8, &ParallelSortConfig::default());
+        assert_eq!(reports_8.len(), 11);
+        assert_eq!(reports_8.last().unwrap().completed, 11);
+
+        let parallel = ParallelSortConfig {
+            sort_threshold: 16,
+            sub_sort_threshold: 16,
+            ..Default::default()
+        };
+        let hybrid = ParallelSortConfig {
+            hybrid: Some(Hybrid {
+                block_size: 8,
+                base_case: BaseCase::InsertionSort,
+            }),
+            ..parallel.clone()
+        };
+        for &len in &[0, 1, 2, 3, 5, 11, 100, 1000, 4097] {
+            for config in &[
+                ParallelSortConfig::default(),
+                parallel.clone(),
+                hybrid.clone(),
+            ] {
+                let reports = reports(len, config);
+                // どのステップもちょうど1回ずつ報告される
+                let total = reports.first().map_or(0, |p| p.total);
+                assert!(reports.iter().all(|p| p.total == total));
+                let mut completed: Vec<usize> = reports.iter().map(|p| p.completed).collect();
+                completed.sort();
+                assert_eq!(completed, (1..=total).collect::<Vec<_>>());
+                if len > 1 {
+                    assert!(total > 0);
+                }
+            }
+            // ステップの数は並列化の閾値によらない
+            assert_eq!(
+                reports(len, &ParallelSortConfig::default()).len(),
+                reports(len, &parallel).len()
+            );
+        }
+    }
+
+    #[test]
+    fn cancellable_honours_hybrid_and_stats() {
+        let config = ParallelSortConfig {
+            sort_threshold: 64,
+            sub_sort_threshold: 64,
+            hybrid: Some(Hybrid {
+                block_size: 20,
+                base_case: BaseCase::InsertionSort,
+            }),
+            ..Default::default()
+        };
+        let mut x = new_u32_vec(5_000);
+        let expected = sort_by_with_stats(&mut x, &|a, b| a.cmp(b), &config).unwrap();
+
+        let counter = Arc::new(StatsCounter::new());
+        let config = ParallelSortConfig {
+            stats: Some(Arc::clone(&counter)),
+            ..config
+        };
+        let mut x = new_u32_vec(5_000);
+        let cancel = AtomicBool::new(false);
+        assert_eq!(
+            sort_cancellable(&mut x, &Ascending, &config, &cancel, None),
+            Ok(())
+        );
+        assert!(is_sorted_ascending(&x));
+        // 挿入ソートでソートしたブロックの比較も含めて、sort_by_with_stats と同じ回数になる
+        assert_eq!(counter.snapshot(), expected);
+    }
+
+    #[test]
+    fn cancel_from_progress() {
+        // 3つ目のステップを終えたところで中断する
+        let config = ParallelSortConfig {
+            sort_threshold: 256,
+            sub_sort_threshold: 256,
+            ..Default::default()
+        };
+        let cancel = AtomicBool::new(false);
+        let progress = |p: Progress| {
+            if p.completed == 3 {
+                cancel.store(true, AtomicOrdering::Relaxed);
+            }
+        };
+        let mut x = new_u32_vec(5_000);
+        assert_eq!(
+            sort_cancellable(&mut x, &Ascending, &config, &cancel, Some(&progress)),
+            Err(SortError::Cancelled)
+        );
+        // x は入力の並べ替えのまま
+        assert!(!is_sorted_ascending(&x));
+        x.sort();
+        let mut expected = new_u32_vec(5_000);
+        expected.sort();
+        assert_eq!(x, expected);
+    }
+
+    #[test]
+    fn cancel_before_start() {
+        let calls = AtomicUsize::new(0);
+        let cancel = AtomicBool::new(true);
+        let mut x = new_u32_vec(100);
+        let result = sort_by_cancellable(
+            &mut x,
+            &|a, b| {
+                calls.fetch_add(1, AtomicOrdering::Relaxed);
+                a.cmp(b)
+            },
+            &ParallelSortConfig::default(),
+            &cancel,
+            None,
+        );
+        assert_eq!(result, Err(SortError::Cancelled));
+        assert_eq!(calls.load(AtomicOrdering::Relaxed), 0);
+        assert_eq!(x, new_u32_vec(100));
+    }
+
+    #[test]
+    fn with_config_runs_in_pool() {
+        // *_with_config はどれも、比較を config のスレッドプールの中で実行する
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .thread_name(|i| format!("service-{}", i))
+            .build()
+            .unwrap();
+        let config = ParallelSortConfig {
+            sort_threshold: 64,
+            sub_sort_threshold: 64,
+            thread_pool: Some(Arc::new(pool)),
+            ..Default::default()
+        };
+        let outside_pool = AtomicBool::new(false);
+        let comparator = |a: &u32, b: &u32| {
+            let name = thread::current().name().unwrap_or("").to_string();
+            if !name.starts_with("service-") {
+                outside_pool.store(true, AtomicOrdering::Relaxed);
+            }
+            a.cmp(b)
+        };
+
+        let mut x = new_u32_vec(1000);
+        assert!(argsort_by_with_config(&x, &comparator, &config).is_ok());
+        assert_eq!(
+            sort_by_stable_with_config(&mut x, &comparator, &config),
+            Ok(())
+        );
+        assert!(is_sorted_ascending(&x));
+
+        let mut keys = new_u32_vec(1000);
+        let mut values: Vec<usize> = (0..1000).collect();
+        assert_eq!(
+            sort_pairs_with_config(&mut keys, &mut values, &Ascending, &config),
+            Ok(())
+        );
+        assert!(is_sorted_ascending(&keys));
+
+        let mut x = new_u32_vec(1000);
+        x[..400].sort();
+        x[400..].sort();
+        assert_eq!(
+            bitonic_merge_by_with_config(&mut x, 400, &comparator, &config),
+            Ok(())
+        );
+        assert!(is_sorted_ascending(&x));
+
+        let mut x = new_u32_vec(1000);
+        assert_eq!(
+            partial_sort_by_with_config(&mut x, 100, &comparator, &config),
+            Ok(())
+        );
+        assert!(is_sorted_ascending(&x[..100]));
+
+        let mut x = new_u32_vec(1000);
+        assert_eq!(
+            sort_batch_by_with_config(&mut x, 100, &comparator, &config),
+            Ok(())
+        );
+        assert!(x.chunks(100).all(is_sorted_ascending));
+
+        let mut x = new_u32_vec(1000);
+        assert_eq!(
+            sort_segments_by_with_config(
+                &mut x,
+                &[0, 300, 1000],
+                &[Ascending, Ascending],
+                &comparator,
+                &config
+            ),
+            Ok(())
+        );
+        assert!(is_sorted_ascending(&x[..300]) && is_sorted_ascending(&x[300..]));
+
+        let mut x = new_u32_vec(1000);
+        let result: Result<(), ()> =
+            try_sort_by_with_config(&mut x, &|a, b| Ok(comparator(a, b)), &config);
+        assert_eq!(result, Ok(()));
+        assert!(is_sorted_ascending(&x));
+
+        assert!(!outside_pool.load(AtomicOrdering::Relaxed));
+    }
+
+    #[test]
+    fn cancel_from_another_thread() {
+        // UI スレッドなど、別のスレッドからトークンを立てて中断する
+        // ソートは config のスレッドプールの中で実行される
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .thread_name(|i| format!("sort-{}", i))
+            .build()
+            .unwrap();
+        let config = ParallelSortConfig {
+            sort_threshold: 1024,
+            sub_sort_threshold: 1024,
+            thread_pool: Some(Arc::new(pool)),
+            ..Default::default()
+        };
+        let cancel = Arc::new(AtomicBool::new(false));
+        let started = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let cancel = Arc::clone(&cancel);
+            let started = Arc::clone(&started);
+            thread::spawn(move || {
+                let outside_pool = AtomicBool::new(false);
+                let mut x = new_u32_vec(1 << 20);
+                let progress = |_: Progress| started.store(true, AtomicOrdering::SeqCst);
+                // 比較を遅くして、中断する前にソートが終わらないようにする
+                let result = sort_by_cancellable(
+                    &mut x,
+                    &|a, b| {
+                        let name = thread::current().name().unwrap_or("").to_string();
+                        if !name.starts_with("sort-") {
+                            outside_pool.store(true, AtomicOrdering::Relaxed);
+                        }
+                        thread::yield_now();
+                        a.cmp(b)
+                    },
+                    &config,
+                    &cancel,
+                    Some(&progress),
+                );
+                (result, x, outside_pool.into_inner())
+            })
+        };
+        while !started.load(AtomicOrdering::SeqCst) {
+            thread::yield_now();
+        }
+        cancel.store(true, AtomicOrdering::SeqCst);
+
+        let (result, mut x, outside_pool) = handle.join().unwrap();
+        assert_eq!(result, Err(SortError::Cancelled));
+        assert!(!outside_pool);
+        x.sort();
+        let mut expected = new_u32_vec(1 << 20);
+        expected.sort();
+        assert_eq!(x, expected);
+    }
+
+    #[test]
+    fn sort_non_power_of_two() {
         let mut x = vec![10, 30, 11];
-        assert!(sort(&mut x, &Ascending).is_err())
+        assert_eq!(sort(&mut x, &Ascending), Ok(()));
+        assert_eq!(x, vec![10, 11, 30]);
+    }
+
+    #[test]
+    fn sort_every_length() {
+        // 0から 300要素まで、すべての長さについて昇順と降順を検証する
+        for len in 0..=300 {
+            let mut x = new_u32_vec(len);
+            assert_eq!(sort(&mut x, &Ascending), Ok(()));
+            assert!(is_sorted_ascending(&x));
+
+            let mut x = new_u32_vec(len);
+            assert_eq!(sort(&mut x, &Descending), Ok(()));
+            assert!(is_sorted_descending(&x));
+        }
+    }
+
+    #[test]
+    fn sort_comparator_panicked() {
+        let comparator = |a: &u32, b: &u32| {
+            if *a == 11 || *b == 11 {
+                panic!("11 is not comparable");
+            }
+            a.cmp(b)
+        };
+        // sort_by はパニックをそのまま伝える
+        let mut x = vec![10, 30, 11, 20];
+        let payload =
+            panic::catch_unwind(AssertUnwindSafe(|| sort_by(&mut x, &comparator))).unwrap_err();
+        assert_eq!(
+            payload.downcast_ref::<&str>(),
+            Some(&"11 is not comparable")
+        );
+        x.sort();
+        assert_eq!(x, vec![10, 11, 20, 30]);
+
+        // sort_by_catching は Err にする
+        let mut x = vec![10, 30, 11, 20];
+        let result = sort_by_catching(&mut x, &comparator, &ParallelSortConfig::default());
+        assert_eq!(
+            result,
+            Err(SortError::ComparatorPanicked(
+                "11 is not comparable".to_string()
+            ))
+        );
+        // パニックしても要素は失われない
+        x.sort();
+        assert_eq!(x, vec![10, 11, 20, 30]);
     }
 
     // #[test]
@@ -305,6 +2512,135 @@ mod tests {
     // }
     // error[E0277]: the trait bound `{float}: std::cmp::Ord` is not satisfied
 
+    #[test]
+    fn sort_with_custom_thread_pool() {
+        // 2スレッドだけのスレッドプールを作り、その中でソートする
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let config = ParallelSortConfig {
+            sort_threshold: 64,
+            sub_sort_threshold: 128,
+            thread_pool: Some(Arc::new(pool)),
+            max_depth: Some(3),
+            hybrid: None,
+            stats: None,
+        };
+
+        let mut x = new_u32_vec(10_000);
+        assert_eq!(sort_with_config(&mut x, &Descending, &config), Ok(()));
+        assert!(is_sorted_descending(&x));
+
+        // SIMD 版に切り替わる i32 も config のスレッドプールでソートする
+        let mut x: Vec<i32> = new_u32_vec(10_000).into_iter().map(|v| v as i32).collect();
+        assert_eq!(sort_with_config(&mut x, &Descending, &config), Ok(()));
+        assert!(is_sorted_descending(&x));
+
+        let mut x = new_u32_vec(10_000);
+        assert_eq!(
+            sort_by_with_config(&mut x, &|a, b| a.cmp(b), &config),
+            Ok(())
+        );
+        assert!(is_sorted_ascending(&x));
+    }
+
+    #[test]
+    fn sort_hybrid() {
+        // ブロックの大きさが2のべき乗でなくても、前半と後半の長さが違ってもソートできる
+        for &base_case in &[BaseCase::InsertionSort, BaseCase::SortUnstable] {
+            for &block_size in &[1, 7, 16, 100] {
+                let config = ParallelSortConfig {
+                    sort_threshold: 256,
+                    sub_sort_threshold: 256,
+                    hybrid: Some(Hybrid {
+                        block_size,
+                        base_case,
+                    }),
+                    ..Default::default()
+                };
+                for &len in &[0, 1, 5, 100, 1000, 20_000] {
+                    let mut x = new_u32_vec(len);
+                    assert_eq!(sort_with_config(&mut x, &Descending, &config), Ok(()));
+                    assert!(is_sorted_descending(&x));
+
+                    let mut expected = new_u32_vec(len);
+                    expected.sort();
+                    let mut x = new_u32_vec(len);
+                    assert_eq!(
+                        sort_by_with_config(&mut x, &|a, b| a.cmp(b), &config),
+                        Ok(())
+                    );
+                    assert_eq!(x, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sort_hybrid_comparator_panicked() {
+        let config = ParallelSortConfig {
+            hybrid: Some(Hybrid {
+                block_size: 4,
+                base_case: BaseCase::SortUnstable,
+            }),
+            ..Default::default()
+        };
+        let mut x: Vec<u32> = vec![10, 30, 11, 20, 4, 330, 21, 110];
+        let result = sort_by_catching(
+            &mut x,
+            &|a: &u32, b: &u32| {
+                if *a == 11 || *b == 11 {
+                    panic!("11 is not comparable");
+                }
+                a.cmp(b)
+            },
+            &config,
+        );
+        assert_eq!(
+            result,
+            Err(SortError::ComparatorPanicked(
+                "11 is not comparable".to_string()
+            ))
+        );
+        // base_case の中でパニックしても要素は失われない
+        x.sort();
+        assert_eq!(x, vec![4, 10, 11, 20, 21, 30, 110, 330]);
+    }
+
+    #[test]
+    fn auto_tune_picks_candidate_thresholds() {
+        let config = ParallelSortConfig {
+            max_depth: Some(4),
+            ..Default::default()
+        }
+        .auto_tune(1 << 12);
+        assert!(config.sort_threshold.is_power_of_two());
+        assert!(config.sub_sort_threshold.is_power_of_two());
+        assert_eq!(config.max_depth, Some(4));
+
+        let mut x = new_u32_vec(5000);
+        assert_eq!(sort_with_config(&mut x, &Ascending, &config), Ok(()));
+        assert!(is_sorted_ascending(&x));
+    }
+
+    #[test]
+    fn auto_tune_does_not_record_stats() {
+        let counter = Arc::new(StatsCounter::new());
+        let config = ParallelSortConfig {
+            stats: Some(Arc::clone(&counter)),
+            ..Default::default()
+        }
+        .auto_tune(1 << 10);
+        // 計測のためのソートは記録せず、返した config には同じカウンタが設定されている
+        assert_eq!(counter.snapshot(), SortStats::default());
+        assert!(Arc::ptr_eq(config.stats.as_ref().unwrap(), &counter));
+
+        let mut x = new_u32_vec(1000);
+        assert_eq!(sort_with_config(&mut x, &Ascending, &config), Ok(()));
+        assert!(counter.snapshot().comparisons > 0);
+    }
+
     #[test]
     fn sort_u32_large() {
         {
@@ -320,5 +2656,52 @@ mod tests {
             assert_eq!(sort(&mut x, &Descending), Ok(()));
             assert!(is_sorted_descending(&x));
         };
+        {
+            // 2のべき乗でない要素数
+            let mut x = new_u32_vec(50_000);
+            assert_eq!(sort(&mut x, &Ascending), Ok(()));
+            assert!(is_sorted_ascending(&x));
+        };
+    }
+
+    #[test]
+    fn sort_simd_u32_i32_f32() {
+        // u32 と i32 は sort が SIMD 版に切り替える
+        let mut x = new_u32_vec(50_000);
+        assert_eq!(sort(&mut x, &Ascending), Ok(()));
+        assert!(is_sorted_ascending(&x));
+
+        let mut x: Vec<i32> = new_u32_vec(50_000).into_iter().map(|v| v as i32).collect();
+        assert_eq!(sort(&mut x, &Descending), Ok(()));
+        assert!(is_sorted_descending(&x));
+
+        // f32 は sort_floats が totalOrder のときに SIMD 版に切り替える
+        let mut x: Vec<f32> = new_u32_vec(50_000)
+            .into_iter()
+            .map(|v| v as f32 - 1e9)
+            .collect();
+        x.extend_from_slice(&[f32::NAN, -0.0, 0.0]);
+        let mut expected = x.clone();
+        expected.sort_by(|a, b| a.total_cmp(b));
+        assert_eq!(
+            sort_floats(&mut x, &Ascending, &FloatOrder::default()),
+            Ok(())
+        );
+        let bits = |v: &[f32]| v.iter().map(|f| f.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&x), bits(&expected));
+
+        // SIMD 版に対応していない型は comparator でソートする
+        let mut x: Vec<u64> = new_u32_vec(5_000).into_iter().map(u64::from).collect();
+        assert_eq!(sort(&mut x, &Ascending), Ok(()));
+        assert!(is_sorted_ascending(&x));
+    }
+
+    #[test]
+    fn sort_borrowed_str() {
+        // sort には 'static 境界がないので、ローカル変数を借用した要素もソートできる
+        let owned: Vec<String> = (0..100).map(|i| format!("{:03}", (i * 37) % 100)).collect();
+        let mut x: Vec<&str> = owned.iter().map(|s| s.as_str()).collect();
+        assert_eq!(sort(&mut x, &Ascending), Ok(()));
+        assert!(is_sorted_ascending(&x));
     }
 }
@@ -0,0 +1,251 @@
+// 再帰を使わないバイトニックソート
+// do_sort / sub_sort の再帰の代わりに、ステージ k（マージするブロックの大きさ）と
+// ステップ j（比較する2要素の距離）の二重ループで比較器を並べる
+//
+// third::sort_by と同じ比較器を実行するので、comparator が Equal を返す要素どうしの順序も含めて
+// 結果は third::sort_by と一致する
+// - ステージ k のブロックは、third::do_sort の再帰で深さが同じ部分（要素数 k 以下）にあたる
+//   要素数が2のべき乗でないと再帰は len / 2 で分割するので、ブロックの大きさはそろわない
+//   ブロックの先頭と要素数、ソートの向きは、再帰と同じ分割をたどって求める（blocks）
+// - ブロックの中の比較相手は、ブロックの先頭からの位置 r に対して r ^ j
+//   （third::sub_sort の再帰と同じ比較器で、範囲外を指すものはパディングなので飛ばす）
+// 同じ要素に触れる比較器の順序は再帰と変わらないので、実行する順序を入れ替えても結果は同じになる
+use super::fourth::PARALLEL_THRESHOLD;
+use super::{SortError, SortOrder};
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::mem;
+
+pub fn sort_by<T, F>(x: &mut [T], comparator: &F) -> Result<(), SortError>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    do_sort(x, comparator);
+    Ok(())
+}
+
+pub fn sort<T: Ord>(x: &mut [T], order: &SortOrder) -> Result<(), SortError> {
+    match *order {
+        SortOrder::Ascending => sort_by(x, &|a, b| a.cmp(b)),
+        SortOrder::Descending => sort_by(x, &|a, b| b.cmp(a)),
+    }
+}
+
+// rayon による並列版。rayon::join で再帰を分岐させる代わりに、
+// ステップごとにスライスを par_chunks_mut で分割して並列に比較する
+pub fn par_sort_by<T, F>(x: &mut [T], comparator: &F) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    do_par_sort(x, comparator);
+    Ok(())
+}
+
+pub fn par_sort<T: Ord + Send>(x: &mut [T], order: &SortOrder) -> Result<(), SortError> {
+    match *order {
+        SortOrder::Ascending => par_sort_by(x, &|a, b| a.cmp(b)),
+        SortOrder::Descending => par_sort_by(x, &|a, b| b.cmp(a)),
+    }
+}
+
+// ステージを (k, j) の組として順に返す
+// k はマージするブロックの大きさ（2, 4, ...）、j は比較する2要素の距離（k/2, k/4, ..., 1）
+fn stages(len: usize) -> impl Iterator<Item = (usize, usize)> {
+    (1..)
+        .map(|e| 1usize << e)
+        .take_while(move |&k| k / 2 < len)
+        .flat_map(|k| {
+            (1..)
+                .map(move |e| k >> e)
+                .take_while(|&j| j >= 1)
+                .map(move |j| (k, j))
+        })
+}
+
+// ステージ k でマージするブロックを、(先頭のインデックス, 要素数, forward) として先頭から順に返す
+// third::do_sort と同じく、前半（len / 2 要素）を逆向きに、後半を同じ向きにする分割を、
+// 要素数を2のべき乗に切り上げた長さが k になるまでたどる
+// 2要素未満のブロックは比較器がないので返さない
+fn blocks(len: usize, k: usize) -> impl Iterator<Item = (usize, usize, bool)> {
+    let depth = (len.next_power_of_two() / k).trailing_zeros();
+    (0..(1usize << depth))
+        .map(move |b| {
+            let (mut start, mut n, mut forward) = (0, len, true);
+            // b のビットを上からたどり、0 なら前半、1 なら後半に進む
+            for bit in (0..depth).rev() {
+                let half = n / 2;
+                if b >> bit & 1 == 1 {
+                    start += half;
+                    n -= half;
+                } else {
+                    n = half;
+                    forward = !forward;
+                }
+            }
+            (start, n, forward)
+        })
+        .filter(|&(_, n, _)| n > 1)
+}
+
+// blocks が返すブロックを、x を分割したスライスとして返す
+fn split_blocks<T>(x: &mut [T], k: usize) -> Vec<(&mut [T], bool)> {
+    let len = x.len();
+    let mut rest = x;
+    let mut offset = 0;
+    let mut result = Vec::new();
+    for (start, n, forward) in blocks(len, k) {
+        // 借用チェッカーを通すため、rest をいったん取り出してから分割する
+        let (block, tail) = mem::take(&mut rest)[(start - offset)..].split_at_mut(n);
+        rest = tail;
+        offset = start + n;
+        result.push((block, forward));
+    }
+    result
+}
+
+fn do_sort<T, F>(x: &mut [T], comparator: &F)
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    for (k, j) in stages(x.len()) {
+        for (start, n, forward) in blocks(x.len(), k) {
+            // ひとつのステップの比較器は、ブロックの先頭から数えた長さ 2j のかたまりの中で閉じている
+            for chunk in x[start..(start + n)].chunks_mut(2 * j) {
+                compare_and_swap(chunk, j, forward, comparator);
+            }
+        }
+    }
+}
+
+fn do_par_sort<T, F>(x: &mut [T], comparator: &F)
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    // 要素数が閾値未満なら並列化しない
+    if x.len() < PARALLEL_THRESHOLD {
+        do_sort(x, comparator);
+        return;
+    }
+
+    for (k, j) in stages(x.len()) {
+        // ブロックやかたまりが小さすぎるとタスクの管理コストが勝つので、
+        // ひとつのタスクがおよそ PARALLEL_THRESHOLD 要素を受け持つようにまとめる
+        let min_blocks = (PARALLEL_THRESHOLD / k).max(1);
+        let min_chunks = (PARALLEL_THRESHOLD / (2 * j)).max(1);
+        split_blocks(x, k)
+            .into_par_iter()
+            .with_min_len(min_blocks)
+            .for_each(|(block, forward)| {
+                block
+                    .par_chunks_mut(2 * j)
+                    .with_min_len(min_chunks)
+                    .for_each(|chunk| compare_and_swap(chunk, j, forward, comparator))
+            });
+    }
+}
+
+// 長さ 2j のかたまりの中で、ステップ j の比較器を実行する
+// 比較の仕方は third::compare_and_swap と同じ
+fn compare_and_swap<T, F>(chunk: &mut [T], j: usize, forward: bool, comparator: &F)
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    let swap_condition = if forward {
+        Ordering::Greater
+    } else {
+        Ordering::Less
+    };
+    for i in 0..j {
+        // かたまりの中では i ^ j == i + j。相手がかたまりの外（パディング）なら比較しない
+        let partner = i ^ j;
+        if partner < chunk.len() && comparator(&chunk[i], &chunk[partner]) == swap_condition {
+            chunk.swap(i, partner);
+        }
+    }
+}
+
+// このモジュールは cargo test を実行したときのみコンパイルされる
+#[cfg(test)]
+mod tests {
+    use super::{par_sort, par_sort_by, sort, sort_by};
+    use crate::third;
+    use crate::utils::{is_sorted_ascending, is_sorted_descending, new_u32_vec};
+    use crate::SortOrder::*;
+
+    #[test]
+    fn sort_32_ascending() {
+        let mut x: Vec<u32> = vec![10, 30, 11, 20, 4, 330, 21, 110];
+        assert_eq!(sort(&mut x, &Ascending), Ok(()));
+        assert_eq!(x, vec![4, 10, 11, 20, 21, 30, 110, 330]);
+    }
+
+    #[test]
+    fn sort_32_descending() {
+        let mut x: Vec<u32> = vec![10, 30, 11, 20, 4, 330, 21, 110];
+        assert_eq!(par_sort(&mut x, &Descending), Ok(()));
+        assert_eq!(x, vec![330, 110, 30, 21, 20, 11, 10, 4]);
+    }
+
+    #[test]
+    fn same_result_as_third() {
+        // 0から 300要素まで、すべての長さについて third::sort_by と結果が一致する
+        for len in 0..=300 {
+            let x = new_u32_vec(len);
+            let comparator = |a: &u32, b: &u32| b.cmp(a);
+
+            let mut expected = x.clone();
+            third::sort_by(&mut expected, &comparator).unwrap();
+
+            let mut actual = x.clone();
+            assert_eq!(sort_by(&mut actual, &comparator), Ok(()));
+            assert_eq!(actual, expected);
+
+            let mut actual = x.clone();
+            assert_eq!(par_sort_by(&mut actual, &comparator), Ok(()));
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn same_result_as_third_with_ties() {
+        // キーだけを比較するので、キーが等しい要素の順序はソートの実装によって変わる
+        // 同じ比較器を同じ順序で実行していれば、その順序まで third::sort_by と一致する
+        let comparator = |a: &(u32, usize), b: &(u32, usize)| a.0.cmp(&b.0);
+        for len in (0..=300).chain(vec![5_000, 10_000]) {
+            let x: Vec<(u32, usize)> = new_u32_vec(len)
+                .into_iter()
+                .map(|v| v % 5)
+                .zip(0..)
+                .collect();
+
+            let mut expected = x.clone();
+            third::sort_by(&mut expected, &comparator).unwrap();
+
+            let mut actual = x.clone();
+            assert_eq!(sort_by(&mut actual, &comparator), Ok(()));
+            assert_eq!(actual, expected);
+
+            let mut actual = x.clone();
+            assert_eq!(par_sort_by(&mut actual, &comparator), Ok(()));
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn sort_u32_large() {
+        let mut x = new_u32_vec(65536);
+        assert_eq!(par_sort(&mut x, &Ascending), Ok(()));
+        assert!(is_sorted_ascending(&x));
+
+        // 2のべき乗でない要素数
+        let mut x = new_u32_vec(50_000);
+        assert_eq!(par_sort(&mut x, &Descending), Ok(()));
+        assert!(is_sorted_descending(&x));
+
+        let mut expected = new_u32_vec(50_000);
+        third::sort(&mut expected, &Descending).unwrap();
+        assert_eq!(x, expected);
+    }
+}
@@ -1,11 +1,143 @@
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
 // SortOrderを列挙型として定義する
+// ソーティングネットワークの比較器の向きにも使うので、比較やコピーができるようにしておく
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SortOrder {
     // SortOrderには2つのバリアントがある
     Ascending,  // 昇順
     Descending, // 降順
 }
+
+// ソート関数が返すエラーを列挙型として定義する
+// 呼び出し側は文字列を調べなくても、バリアントで何が起きたかを判別できる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SortError {
+    // 要素数が2のべき乗でない（second のように2のべき乗しか扱えないソートで発生する）
+    NotPowerOfTwo { len: usize },
+    // 比較関数（comparator）がパニックした。catching などでパニックを捕捉したときに返す
+    ComparatorPanicked(String),
+    // 一緒に並べ替えるキーと値のスライスの要素数が異なる
+    LengthMismatch { keys: usize, values: usize },
+    // 出力先のスライスの要素数が、入力の要素数の合計と異なる
+    OutputLengthMismatch { expected: usize, actual: usize },
+    // 行ごとにソートするデータの要素数が、行の長さで割り切れない
+    RowLengthMismatch { len: usize, row_len: usize },
+    // セグメントの区切り（offsets）が不正。index 番目の値が前の値より小さいか、範囲外
+    InvalidOffsets { index: usize },
+    // セグメントの数とソート順（orders）の数が異なる
+    SegmentCountMismatch { segments: usize, orders: usize },
+    // キャンセルトークンによってソートが中断された
+    Cancelled,
+    // マージする前半と後半の境目（mid）が、スライスの要素数を超えている
+    MidOutOfRange { mid: usize, len: usize },
+    // 再生しようとしたトレースの要素数が、再生できる上限（trace::MAX_REPLAY_LEN）を超えている
+    TooManyToReplay { len: usize, max: usize },
+}
+
+// Display トレイトを実装すると、println! の {} でエラーを表示できる
+impl fmt::Display for SortError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SortError::NotPowerOfTwo { len } => write!(
+                f,
+                "The length of x is not a power of two. (x.len(): {})",
+                len
+            ),
+            SortError::ComparatorPanicked(message) => {
+                write!(f, "The comparator panicked: {}", message)
+            }
+            SortError::LengthMismatch { keys, values } => write!(
+                f,
+                "The lengths of keys and values differ. (keys.len(): {}, values.len(): {})",
+                keys, values
+            ),
+            SortError::OutputLengthMismatch { expected, actual } => write!(
+                f,
+                "The length of out differs from the total length of the inputs. (expected: {}, out.len(): {})",
+                expected, actual
+            ),
+            SortError::RowLengthMismatch { len, row_len } => write!(
+                f,
+                "The length of data is not a multiple of row_len. (data.len(): {}, row_len: {})",
+                len, row_len
+            ),
+            SortError::InvalidOffsets { index } => {
+                write!(f, "The offsets are invalid at index {}.", index)
+            }
+            SortError::SegmentCountMismatch { segments, orders } => write!(
+                f,
+                "The numbers of segments and orders differ. (segments: {}, orders.len(): {})",
+                segments, orders
+            ),
+            SortError::Cancelled => write!(f, "The sort was cancelled."),
+            SortError::MidOutOfRange { mid, len } => write!(
+                f,
+                "mid is out of range for x. (mid: {}, x.len(): {})",
+                mid, len
+            ),
+            SortError::TooManyToReplay { len, max } => write!(
+                f,
+                "The trace has too many elements to replay. (len: {}, max: {})",
+                len, max
+            ),
+        }
+    }
+}
+
+// Error トレイトは Debug と Display が実装されていれば、中身は空でよい
+impl Error for SortError {}
+
+// f の実行中に起きたパニックを捕捉して SortError::ComparatorPanicked に変換する
+// 例：catching(|| fourth::sort(&mut x, &SortOrder::Ascending))
+//
+// comparator（や Ord::cmp）がパニックしたときの保証：
+// - ソート関数はパニックを捕捉せず、元のパニックがそのまま呼び出し元に伝わる
+//   Err として受け取りたいときは、catching で囲むか third::sort_by_catching、
+//   fourth::sort_by_catching を使う
+// - どちらの場合も、スライスは元の要素を並べ替えたもののままになり、要素が失われたり、
+//   二重に解放されたり、複製されたりすることはない。理由は次のとおり
+//   - このクレートのソートは、比較を終えてから swap で要素を交換する。array::sort_array_copy は
+//     Copy 型の値を書き戻すだけなので、値を読み出してから書き戻すまでの間にパニックは起きない
+//   - ハイブリッドモードの BaseCase::SortUnstable が使う標準ライブラリの sort_unstable_by は、
+//     比較関数がパニックしても、スライスが元の要素の並べ替えのままであることを保証している
+// - fourth などの並列版で rayon::join の片方がパニックしても、rayon はもう片方のタスクが
+//   終わるのを待ってからパニックを伝える。関数から戻ったあとにスライスを触るスレッドは残らない
+// 上の保証により、パニックしたあとのスライスを使っても問題ないので AssertUnwindSafe で包む
+pub fn catching<R, F>(f: F) -> Result<R, SortError>
+where
+    F: FnOnce() -> Result<R, SortError>,
+{
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(SortError::ComparatorPanicked(panic_message(payload))))
+}
+
+// panic! に渡されたメッセージは &str か String のどちらかで渡ってくる
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+pub mod array;
 pub mod first;
+pub mod float;
 pub mod fourth;
+pub mod iterative;
+pub mod network;
+pub mod odd_even;
 pub mod second;
+pub mod simd;
+pub mod sorter;
+pub mod stats;
 pub mod third;
+pub mod trace;
 pub mod utils;
+pub mod verify;
@@ -0,0 +1,364 @@
+// ソーティングネットワークを比較器のリストとして明示的に組み立てる
+// third::do_sort / sub_sort の再帰や odd_even のステージが暗黙に定めている比較器を
+// 書き出すので、ネットワークを Graphviz の DOT や SVG の図にしてレビューできる
+use super::{odd_even, SortOrder};
+use std::cmp::Ordering;
+use std::fmt::Write;
+
+// 比較器。i < j で、比較と交換のあと (x[i], x[j]) が order の順に並ぶ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Comparator {
+    pub i: usize,
+    pub j: usize,
+    pub order: SortOrder,
+}
+
+// 要素数 len のソーティングネットワーク
+// stages の各要素は、互いに重ならない（同時に実行できる）比較器の集まり
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Network {
+    pub len: usize,
+    pub stages: Vec<Vec<Comparator>>,
+}
+
+impl Network {
+    // 比較器を実行順に並べたリストからネットワークを作る
+    // 各比較器は、同じ要素に触れる直前の比較器の次のステージに置く
+    pub fn from_comparators(len: usize, comparators: &[Comparator]) -> Self {
+        let mut stages: Vec<Vec<Comparator>> = Vec::new();
+        // 要素ごとに、最後に触れた比較器のステージの次の番号を覚えておく
+        let mut next_stage = vec![0; len];
+        for &c in comparators {
+            assert!(c.i < c.j && c.j < len, "invalid comparator: {:?}", c);
+            let stage = next_stage[c.i].max(next_stage[c.j]);
+            if stage == stages.len() {
+                stages.push(Vec::new());
+            }
+            stages[stage].push(c);
+            next_stage[c.i] = stage + 1;
+            next_stage[c.j] = stage + 1;
+        }
+        Self { len, stages }
+    }
+
+    // third::sort_by と同じ比較器からなるバイトニックソートのネットワーク
+    pub fn bitonic(len: usize) -> Self {
+        let mut comparators = Vec::new();
+        bitonic_sort(&mut comparators, 0, len, true);
+        Self::from_comparators(len, &comparators)
+    }
+
+    // odd_even::sort_by と同じ比較器からなる奇偶マージソートのネットワーク
+    pub fn odd_even(len: usize) -> Self {
+        let mut comparators = Vec::new();
+        for (p, k) in odd_even::stages(len) {
+            let mut j = k % p;
+            while j + k < len {
+                for i in j..(j + k).min(len - k) {
+                    if i / (2 * p) == (i + k) / (2 * p) {
+                        comparators.push(Comparator {
+                            i,
+                            j: i + k,
+                            order: SortOrder::Ascending,
+                        });
+                    }
+                }
+                j += 2 * k;
+            }
+        }
+        Self::from_comparators(len, &comparators)
+    }
+
+    // 比較器を実行順に返す
+    pub fn comparators(&self) -> impl Iterator<Item = &Comparator> {
+        self.stages.iter().flatten()
+    }
+
+    // ステージの数（ネットワークの深さ）
+    pub fn depth(&self) -> usize {
+        self.stages.len()
+    }
+
+    // ネットワークで x をソートする。comparator が Less を返す要素を前に置く
+    pub fn apply_by<T, F>(&self, x: &mut [T], comparator: &F)
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        assert_eq!(x.len(), self.len);
+        for c in self.comparators() {
+            let swap_condition = match c.order {
+                SortOrder::Ascending => Ordering::Greater,
+                SortOrder::Descending => Ordering::Less,
+            };
+            if comparator(&x[c.i], &x[c.j]) == swap_condition {
+                x.swap(c.i, c.j);
+            }
+        }
+    }
+
+    pub fn apply<T: Ord>(&self, x: &mut [T]) {
+        self.apply_by(x, &|a, b| a.cmp(b))
+    }
+
+    // Graphviz の DOT 形式で出力する
+    // 要素（ワイヤー）ごとにステージの数だけ点を横に並べ、比較器は同じステージの点を結ぶ矢印にする
+    // 矢印は大きい方の要素が移る先を指す
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph network {{").unwrap();
+        writeln!(dot, "  rankdir=LR;").unwrap();
+        writeln!(dot, "  node [shape=point];").unwrap();
+        writeln!(dot, "  edge [arrowhead=none];").unwrap();
+
+        // ワイヤー
+        for wire in 0..self.len {
+            let points: Vec<String> = (0..=self.depth() + 1)
+                .map(|t| format!("w{}_{}", wire, t))
+                .collect();
+            writeln!(dot, "  {};", points.join(" -> ")).unwrap();
+        }
+
+        // ステージごとに点を縦に揃え、比較器を描く
+        for (s, stage) in self.stages.iter().enumerate() {
+            let t = s + 1;
+            let points: Vec<String> = (0..self.len).map(|w| format!("w{}_{}", w, t)).collect();
+            writeln!(dot, "  {{ rank=same; {}; }}", points.join("; ")).unwrap();
+            for c in stage {
+                let (from, to) = match c.order {
+                    SortOrder::Ascending => (c.i, c.j),
+                    SortOrder::Descending => (c.j, c.i),
+                };
+                writeln!(
+                    dot,
+                    "  w{}_{} -> w{}_{} [arrowhead=normal, constraint=false];",
+                    from, t, to, t
+                )
+                .unwrap();
+            }
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    // Knuth の図のように、要素を横線、比較器を縦線で描いた SVG を出力する
+    // 比較器の縦線は大きい方の要素が移る先に矢印をつける
+    // 同じステージの比較器でも、縦線が重なるものは横にずらして描く
+    pub fn to_svg(&self) -> String {
+        const MARGIN: usize = 20;
+        const WIRE_GAP: usize = 20;
+        const COLUMN_GAP: usize = 12;
+        const STAGE_GAP: usize = 12;
+
+        // ステージごとに、縦線が重ならないように比較器を列に詰める
+        let mut columns: Vec<Vec<Comparator>> = Vec::new();
+        let mut column_x = Vec::new();
+        let mut x = MARGIN + STAGE_GAP;
+        for stage in &self.stages {
+            let first = columns.len();
+            for &c in stage {
+                let free = columns[first..]
+                    .iter()
+                    .position(|column| column.iter().all(|other| c.j < other.i || other.j < c.i));
+                match free {
+                    Some(k) => columns[first + k].push(c),
+                    None => {
+                        columns.push(vec![c]);
+                        column_x.push(x);
+                        x += COLUMN_GAP;
+                    }
+                }
+            }
+            x += STAGE_GAP;
+        }
+
+        let width = x + MARGIN;
+        let height = MARGIN * 2 + WIRE_GAP * self.len.saturating_sub(1);
+        let wire_y = |wire: usize| MARGIN + WIRE_GAP * wire;
+
+        let mut svg = String::new();
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#,
+            width, height, width, height
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"  <defs><marker id="arrow" viewBox="0 0 10 10" refX="10" refY="5" markerWidth="6" markerHeight="6" orient="auto"><path d="M0,0 L10,5 L0,10 z"/></marker></defs>"#
+        )
+        .unwrap();
+        writeln!(svg, r#"  <rect width="100%" height="100%" fill="white"/>"#).unwrap();
+
+        for wire in 0..self.len {
+            writeln!(
+                svg,
+                r#"  <line x1="{}" y1="{}" x2="{}" y2="{}" stroke="black"/>"#,
+                MARGIN,
+                wire_y(wire),
+                width - MARGIN,
+                wire_y(wire)
+            )
+            .unwrap();
+        }
+
+        for (column, &x) in columns.iter().zip(&column_x) {
+            for c in column {
+                let (from, to) = match c.order {
+                    SortOrder::Ascending => (c.i, c.j),
+                    SortOrder::Descending => (c.j, c.i),
+                };
+                writeln!(svg, r#"  <circle cx="{}" cy="{}" r="3"/>"#, x, wire_y(from)).unwrap();
+                writeln!(
+                    svg,
+                    r#"  <line x1="{}" y1="{}" x2="{}" y2="{}" stroke="black" stroke-width="2" marker-end="url(#arrow)"/>"#,
+                    x,
+                    wire_y(from),
+                    x,
+                    wire_y(to)
+                )
+                .unwrap();
+            }
+        }
+        writeln!(svg, "</svg>").unwrap();
+        svg
+    }
+}
+
+// third::do_sort と同じ再帰で、x[lo..lo + len] をソートする比較器を書き出す
+fn bitonic_sort(comparators: &mut Vec<Comparator>, lo: usize, len: usize, forward: bool) {
+    if len > 1 {
+        let mid_point = len / 2;
+        bitonic_sort(comparators, lo, mid_point, !forward);
+        bitonic_sort(comparators, lo + mid_point, len - mid_point, forward);
+        bitonic_merge(comparators, lo, len, forward);
+    }
+}
+
+// third::sub_sort と compare_and_swap に対応する
+fn bitonic_merge(comparators: &mut Vec<Comparator>, lo: usize, len: usize, forward: bool) {
+    if len > 1 {
+        let mid_point = len.next_power_of_two() / 2;
+        let order = if forward {
+            SortOrder::Ascending
+        } else {
+            SortOrder::Descending
+        };
+        for i in 0..(len - mid_point) {
+            comparators.push(Comparator {
+                i: lo + i,
+                j: lo + mid_point + i,
+                order,
+            });
+        }
+        bitonic_merge(comparators, lo, mid_point, forward);
+        bitonic_merge(comparators, lo + mid_point, len - mid_point, forward);
+    }
+}
+
+// このモジュールは cargo test を実行したときのみコンパイルされる
+#[cfg(test)]
+mod tests {
+    use super::{Comparator, Network};
+    use crate::utils::new_u32_vec;
+    use crate::SortOrder::*;
+    use crate::{odd_even, third};
+
+    #[test]
+    fn bitonic_8() {
+        let network = Network::bitonic(8);
+        // 要素数 n = 2^k のバイトニックソートは、比較器が n/2 * k(k+1)/2 個、深さが k(k+1)/2
+        assert_eq!(network.comparators().count(), 24);
+        assert_eq!(network.depth(), 6);
+        // 最初のステージは隣り合う要素の組を比較する。向きは再帰の方向で決まる
+        assert_eq!(
+            network.stages[0],
+            vec![
+                Comparator {
+                    i: 0,
+                    j: 1,
+                    order: Ascending
+                },
+                Comparator {
+                    i: 2,
+                    j: 3,
+                    order: Descending
+                },
+                Comparator {
+                    i: 4,
+                    j: 5,
+                    order: Descending
+                },
+                Comparator {
+                    i: 6,
+                    j: 7,
+                    order: Ascending
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn odd_even_8() {
+        let network = Network::odd_even(8);
+        assert_eq!(network.comparators().count(), 19);
+        assert_eq!(network.depth(), 6);
+        assert!(network.comparators().all(|c| c.order == Ascending));
+    }
+
+    #[test]
+    fn networks_match_sorters() {
+        // 明示的なネットワークで並べ替えた結果は、元のソート関数の結果と一致する
+        for len in 0..=100 {
+            let x = new_u32_vec(len);
+
+            let mut expected = x.clone();
+            third::sort(&mut expected, &Ascending).unwrap();
+            let mut actual = x.clone();
+            Network::bitonic(len).apply(&mut actual);
+            assert_eq!(actual, expected);
+
+            let mut expected = x.clone();
+            odd_even::sort(&mut expected, &Ascending).unwrap();
+            let mut actual = x.clone();
+            Network::odd_even(len).apply(&mut actual);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn stages_are_disjoint() {
+        for network in &[Network::bitonic(37), Network::odd_even(37)] {
+            for stage in &network.stages {
+                let mut used = vec![false; network.len];
+                for c in stage {
+                    assert!(!used[c.i] && !used[c.j]);
+                    used[c.i] = true;
+                    used[c.j] = true;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn export_dot() {
+        let dot = Network::bitonic(4).to_dot();
+        assert!(dot.starts_with("digraph network {"));
+        // 降順の比較器は、矢印が前の要素を指す
+        assert!(dot.contains("w1_1 -> w0_1 [arrowhead=normal"));
+        assert!(dot.contains("w2_1 -> w3_1 [arrowhead=normal"));
+        assert!(dot.trim_end().ends_with('}'));
+    }
+
+    #[test]
+    fn export_svg() {
+        let network = Network::odd_even(8);
+        let svg = network.to_svg();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        // 比較器ごとに縦線が1本ずつ描かれる
+        assert_eq!(
+            svg.matches(r#"marker-end="url(#arrow)""#).count(),
+            network.comparators().count()
+        );
+    }
+}
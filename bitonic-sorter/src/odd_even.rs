@@ -0,0 +1,164 @@
+// Batcher の奇偶マージソート（odd-even merge sort）
+// バイトニックソートと同じくソーティングネットワークの一種だが、比較器の数が少ない
+// 比較器の向きはすべて同じ（小さい方を前に置く）なので、要素数が2のべき乗でない場合も
+// 末尾を +∞ で埋めたとみなして、範囲外を指す比較器を飛ばすだけでソートできる
+use super::fourth::PARALLEL_THRESHOLD;
+use super::{SortError, SortOrder};
+use rayon::prelude::*;
+use std::cmp::Ordering;
+
+pub fn sort_by<T, F>(x: &mut [T], comparator: &F) -> Result<(), SortError>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    do_sort(x, comparator);
+    Ok(())
+}
+
+pub fn sort<T: Ord>(x: &mut [T], order: &SortOrder) -> Result<(), SortError> {
+    match *order {
+        SortOrder::Ascending => sort_by(x, &|a, b| a.cmp(b)),
+        SortOrder::Descending => sort_by(x, &|a, b| b.cmp(a)),
+    }
+}
+
+// rayon による並列版。ひとつのステージに含まれる比較器は互いに重ならないので、
+// ステージごとにスライスを par_chunks_mut で分割して並列に比較する
+pub fn par_sort_by<T, F>(x: &mut [T], comparator: &F) -> Result<(), SortError>
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    do_par_sort(x, comparator);
+    Ok(())
+}
+
+pub fn par_sort<T: Ord + Send>(x: &mut [T], order: &SortOrder) -> Result<(), SortError> {
+    match *order {
+        SortOrder::Ascending => par_sort_by(x, &|a, b| a.cmp(b)),
+        SortOrder::Descending => par_sort_by(x, &|a, b| b.cmp(a)),
+    }
+}
+
+// ネットワークのステージを (p, k) の組として順に返す
+// p はマージ済みの列の長さ（1, 2, 4, ...）、k は比較する2要素の距離（p, p/2, ..., 1）
+pub(crate) fn stages(len: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..)
+        .map(|e| 1usize << e)
+        .take_while(move |&p| p < len)
+        .flat_map(|p| {
+            (0..)
+                .map(move |e| p >> e)
+                .take_while(|&k| k >= 1)
+                .map(move |k| (p, k))
+        })
+}
+
+fn do_sort<T, F>(x: &mut [T], comparator: &F)
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    for (p, k) in stages(x.len()) {
+        // 比較器は k % p 番目から始まり、2k 要素ごとのかたまりに k 個ずつ含まれる
+        let offset = k % p;
+        for (c, chunk) in x[offset..].chunks_mut(2 * k).enumerate() {
+            compare_and_swap(chunk, offset + c * 2 * k, p, k, comparator);
+        }
+    }
+}
+
+fn do_par_sort<T, F>(x: &mut [T], comparator: &F)
+where
+    T: Send,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    // 要素数が閾値未満なら並列化しない
+    if x.len() < PARALLEL_THRESHOLD {
+        do_sort(x, comparator);
+        return;
+    }
+
+    for (p, k) in stages(x.len()) {
+        let offset = k % p;
+        // かたまりが小さすぎるとタスクの管理コストが勝つので、
+        // ひとつのタスクがおよそ PARALLEL_THRESHOLD 要素を受け持つようにまとめる
+        let min_chunks = (PARALLEL_THRESHOLD / (2 * k)).max(1);
+        x[offset..]
+            .par_chunks_mut(2 * k)
+            .with_min_len(min_chunks)
+            .enumerate()
+            .for_each(|(c, chunk)| compare_and_swap(chunk, offset + c * 2 * k, p, k, comparator));
+    }
+}
+
+// chunk[i] と chunk[i + k] を比較する。start は chunk の先頭の x におけるインデックス
+fn compare_and_swap<T, F>(chunk: &mut [T], start: usize, p: usize, k: usize, comparator: &F)
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    // chunk の末尾より先を指す比較器は、相手がパディングなので比較しない
+    let count = k.min(chunk.len().saturating_sub(k));
+    for i in 0..count {
+        // 2p 要素ずつのブロックをまたぐ組は比較しない
+        if (start + i) / (2 * p) == (start + i + k) / (2 * p)
+            && comparator(&chunk[i], &chunk[i + k]) == Ordering::Greater
+        {
+            chunk.swap(i, i + k);
+        }
+    }
+}
+
+// このモジュールは cargo test を実行したときのみコンパイルされる
+#[cfg(test)]
+mod tests {
+    use super::{par_sort, par_sort_by, sort, sort_by};
+    use crate::utils::{is_sorted_ascending, is_sorted_descending, new_u32_vec};
+    use crate::SortOrder::*;
+
+    #[test]
+    fn sort_32_ascending() {
+        let mut x: Vec<u32> = vec![10, 30, 11, 20, 4, 330, 21, 110];
+        assert_eq!(sort(&mut x, &Ascending), Ok(()));
+        assert_eq!(x, vec![4, 10, 11, 20, 21, 30, 110, 330]);
+    }
+
+    #[test]
+    fn sort_32_descending() {
+        let mut x: Vec<u32> = vec![10, 30, 11, 20, 4, 330, 21, 110];
+        assert_eq!(par_sort(&mut x, &Descending), Ok(()));
+        assert_eq!(x, vec![330, 110, 30, 21, 20, 11, 10, 4]);
+    }
+
+    #[test]
+    fn sort_str_by_length() {
+        let mut x = vec!["Rust", "is", "fast", "and", "memory-efficient"];
+        assert_eq!(sort_by(&mut x, &|a, b| b.len().cmp(&a.len())), Ok(()));
+        assert_eq!(x[0], "memory-efficient");
+        assert_eq!(x[4], "is");
+    }
+
+    #[test]
+    fn sort_every_length() {
+        for len in 0..=300 {
+            let mut x = new_u32_vec(len);
+            assert_eq!(sort(&mut x, &Ascending), Ok(()));
+            assert!(is_sorted_ascending(&x));
+
+            let mut x = new_u32_vec(len);
+            assert_eq!(sort(&mut x, &Descending), Ok(()));
+            assert!(is_sorted_descending(&x));
+        }
+    }
+
+    #[test]
+    fn sort_u32_large() {
+        let mut x = new_u32_vec(65536);
+        assert_eq!(par_sort(&mut x, &Ascending), Ok(()));
+        assert!(is_sorted_ascending(&x));
+
+        // 2のべき乗でない要素数
+        let mut x = new_u32_vec(50_000);
+        assert_eq!(par_sort_by(&mut x, &|a, b| b.cmp(a)), Ok(()));
+        assert!(is_sorted_descending(&x));
+    }
+}
@@ -5,48 +5,63 @@
 //   u32 型は32ビット符号なし整数
 //   [u32] 型はu32のスライス（現時点ではスライスは1次元の配列と考えてよい）
 // 型パラメータＴにトレイト境界Ord（全順序）を追加する
-use super::SortOrder;
+use super::trace::{Observer, Trace};
+use super::{SortError, SortOrder};
 
-pub fn sort<T: Ord>(x: &mut [T], order: &SortOrder) -> Result<(), String> {
+pub fn sort<T: Ord>(x: &mut [T], order: &SortOrder) -> Result<(), SortError> {
+    sort_with_observer(x, order, ())
+}
+
+// sort と同じようにソートし、compare_and_swap で比較した要素を記録したトレースを返す
+pub fn sort_traced<T: Ord + Clone>(x: &mut [T], order: &SortOrder) -> Result<Trace<T>, SortError> {
+    Trace::record(x, |x, tracer| sort_with_observer(x, order, tracer))
+}
+
+fn sort_with_observer<T: Ord, O: Observer<T>>(
+    x: &mut [T],
+    order: &SortOrder,
+    observer: O,
+) -> Result<(), SortError> {
     if x.len().is_power_of_two() {
         match *order {
-            SortOrder::Ascending => do_sort(x, true),
-            SortOrder::Descending => do_sort(x, false),
+            SortOrder::Ascending => do_sort(x, true, observer),
+            SortOrder::Descending => do_sort(x, false, observer),
         };
         Ok(())
     } else {
-        Err(format!(
-            "The length of x is not a power of two. (x.len(): {})",
-            x.len()
-        ))
+        Err(SortError::NotPowerOfTwo { len: x.len() })
     }
 }
 
-fn do_sort<T: Ord>(x: &mut [T], up: bool) {
+fn do_sort<T: Ord, O: Observer<T>>(x: &mut [T], up: bool, observer: O) {
     // 未実装の意味。コンパイルは通るが、実行すると panic する
     // unimplemented!();
 
     if x.len() > 1 {
         let mid_point = x.len() / 2;
-        do_sort(&mut x[..mid_point], true);
-        do_sort(&mut x[mid_point..], false);
-        sub_sort(x, up);
+        do_sort(&mut x[..mid_point], true, observer);
+        do_sort(&mut x[mid_point..], false, observer.shift(mid_point));
+        sub_sort(x, up, observer);
     }
 }
 
-fn sub_sort<T: Ord>(x: &mut [T], up: bool) {
+fn sub_sort<T: Ord, O: Observer<T>>(x: &mut [T], up: bool, observer: O) {
     if x.len() > 1 {
-        compare_and_swap(x, up);
+        compare_and_swap(x, up, observer);
         let mid_point = x.len() / 2;
-        sub_sort(&mut x[..mid_point], up);
-        sub_sort(&mut x[mid_point..], up);
+        sub_sort(&mut x[..mid_point], up, observer);
+        sub_sort(&mut x[mid_point..], up, observer.shift(mid_point));
     }
 }
 
-fn compare_and_swap<T: Ord>(x: &mut [T], up: bool) {
+// observer には比較するたびに通知する。sort では () を渡す
+fn compare_and_swap<T: Ord, O: Observer<T>>(x: &mut [T], up: bool, observer: O) {
     let mid_point = x.len() / 2;
+    observer.stage();
     for i in 0..mid_point {
-        if (x[mid_point + i] < x[i]) == up {
+        let swapped = (x[mid_point + i] < x[i]) == up;
+        observer.compared(i, mid_point + i, &x[i], &x[mid_point + i], swapped);
+        if swapped {
             // 要素を交換する
             x.swap(i, mid_point + i);
         }
@@ -59,6 +74,7 @@ mod tests {
     // 親モジュール（second）のsort関数を使用する
     use super::sort;
     // 列挙型を使う
+    use crate::SortError;
     use crate::SortOrder::*;
 
     // #[test] のついた関数はcargo testしたときに実行される
@@ -143,7 +159,10 @@ mod tests {
     #[test]
     fn sort_to_fail() {
         let mut x = vec![10, 30, 11];
-        assert!(sort(&mut x, &Ascending).is_err())
+        assert_eq!(
+            sort(&mut x, &Ascending),
+            Err(SortError::NotPowerOfTwo { len: 3 })
+        );
     }
 
     // #[test]
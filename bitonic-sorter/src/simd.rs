@@ -0,0 +1,485 @@
+// u32, i32, f32 のスライスに特化したバイトニックソート
+// compare_and_swap を std::arch の SIMD 命令（AVX2 か SSE4.1）の min/max で置き換える
+// 使える命令セットは実行時に判定し、どちらも使えなければスカラー版にフォールバックする
+use super::fourth::ParallelSortConfig;
+use super::{SortError, SortOrder};
+use rayon;
+use rayon::prelude::*;
+use std::any::TypeId;
+use std::marker::PhantomData;
+use std::mem;
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+// SIMD 版のソートに対応した要素型を表すトレイト
+// 外部のクレートで実装されないよう、private モジュールの Sealed トレイトを継承させる
+pub trait SimdElement: Copy + Send + Sync + 'static + private::Sealed {
+    // a[i] と b[i] を比較して、forward が true なら小さい方を a[i] に、大きい方を b[i] に置く
+    // forward が false ならその逆にする
+    #[doc(hidden)]
+    fn compare_and_swap_lanes(a: &mut [Self], b: &mut [Self], forward: bool, isa: Isa);
+}
+
+// Isa も private モジュールに置き、クレートの外からは名前を使えないようにする
+mod private {
+    pub trait Sealed {}
+    impl Sealed for u32 {}
+    impl Sealed for i32 {}
+    impl Sealed for f32 {}
+
+    // 実行時に判定した、使用する命令セット
+    // x86_64 以外では Scalar しか使わない
+    #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Isa {
+        Avx2,
+        Sse41,
+        Scalar,
+    }
+}
+
+use self::private::Isa;
+
+impl Isa {
+    fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                return Isa::Avx2;
+            }
+            if is_x86_feature_detected!("sse4.1") {
+                return Isa::Sse41;
+            }
+        }
+        Isa::Scalar
+    }
+}
+
+// 要素を順次ソートする。f32 に NaN が含まれる場合、NaN は交換されないので
+// 結果の順序は保証されない（要素が失われることはない）
+pub fn sort<T: SimdElement>(x: &mut [T], order: &SortOrder) -> Result<(), SortError> {
+    let isa = Isa::detect();
+    let forward = match *order {
+        SortOrder::Ascending => true,
+        SortOrder::Descending => false,
+    };
+    // 閾値を最大にすれば rayon::join は呼ばれず、順次ソートになる
+    let config = ParallelSortConfig {
+        sort_threshold: usize::MAX,
+        sub_sort_threshold: usize::MAX,
+        ..Default::default()
+    };
+    do_sort(x, forward, isa, &config, 0);
+    Ok(())
+}
+
+// config にしたがって rayon で並列にソートする
+// fourth::sort_with_config から u32 と i32 のスライスに対して呼ばれる
+pub(crate) fn sort_with_config<T: SimdElement>(
+    x: &mut [T],
+    order: &SortOrder,
+    config: &ParallelSortConfig,
+) {
+    let isa = Isa::detect();
+    let forward = match *order {
+        SortOrder::Ascending => true,
+        SortOrder::Descending => false,
+    };
+    config.install(|| do_sort(x, forward, isa, config, 0));
+}
+
+// f32 のスライスを IEEE 754 の totalOrder で並列にソートする
+// fourth::sort_floats から、FloatOrder::default() のときに呼ばれる
+// totalOrder の順に並ぶ u32 のキーに変換して u32 の SIMD 版でソートし、f32 に戻す
+// totalOrder で等しいのはビット列が同じ値どうしだけなので、結果は comparator で比較したときと同じ
+pub(crate) fn sort_f32_total_order(x: &mut [f32], order: &SortOrder, config: &ParallelSortConfig) {
+    config.install(|| {
+        let mut keys: Vec<u32> = x.par_iter().map(|v| total_order_key(*v)).collect();
+        sort_with_config(&mut keys, order, config);
+        x.par_iter_mut()
+            .zip(keys.par_iter())
+            .for_each(|(v, &key)| *v = from_total_order_key(key));
+    })
+}
+
+// 符号ビットが立っていれば（負の数なら）全ビットを反転し、そうでなければ符号ビットだけを立てる
+// こうすると、u32 として比べた順序が totalOrder と一致する
+fn total_order_key(v: f32) -> u32 {
+    let bits = v.to_bits();
+    if bits >> 31 == 1 {
+        !bits
+    } else {
+        bits | 1 << 31
+    }
+}
+
+fn from_total_order_key(key: u32) -> f32 {
+    if key >> 31 == 1 {
+        f32::from_bits(key & !(1 << 31))
+    } else {
+        f32::from_bits(!key)
+    }
+}
+
+// T と U が同じ型なら、スライスを U のスライスとして返す
+// fourth::sort_with_config で、要素型が SIMD 版に対応しているかを判定するために使う
+// T には 'static 境界を付けないので、借用を含む型でも呼べる（その場合は常に None）
+pub(crate) fn downcast_slice_mut<T, U: SimdElement>(x: &mut [T]) -> Option<&mut [U]> {
+    if type_id_of::<T>() == TypeId::of::<U>() {
+        // T と U は同じ型なので、ポインタの型を変えても安全
+        Some(unsafe { std::slice::from_raw_parts_mut(x.as_mut_ptr() as *mut U, x.len()) })
+    } else {
+        None
+    }
+}
+
+// T の TypeId を、T に 'static 境界を付けずに求める
+// TypeId::of は T: 'static を要求するので、トレイトオブジェクトのライフタイムを 'static に
+// 書き換えてから呼ぶ（typeid クレートと同じ方法）。ライフタイムだけが異なる型は同じ TypeId に
+// なるが、比べる相手の SimdElement はライフタイムを持たないので、一致すれば同じ型だと言える
+fn type_id_of<T: ?Sized>() -> TypeId {
+    trait NonStaticAny {
+        fn get_type_id(&self) -> TypeId
+        where
+            Self: 'static;
+    }
+
+    impl<U: ?Sized> NonStaticAny for PhantomData<U> {
+        fn get_type_id(&self) -> TypeId
+        where
+            Self: 'static,
+        {
+            TypeId::of::<U>()
+        }
+    }
+
+    let phantom = PhantomData::<T>;
+    let object: &dyn NonStaticAny = &phantom;
+    // 参照先は変わらず、トレイトオブジェクトのライフタイムだけを延ばす
+    // get_type_id は値を読まずに TypeId を返すだけなので、延ばしても問題ない
+    let object: &(dyn NonStaticAny + 'static) = unsafe { mem::transmute(object) };
+    object.get_type_id()
+}
+
+// 再帰の構造は fourth と同じ
+fn do_sort<T: SimdElement>(
+    x: &mut [T],
+    forward: bool,
+    isa: Isa,
+    config: &ParallelSortConfig,
+    depth: usize,
+) {
+    if x.len() > 1 {
+        let mid_point = x.len() / 2;
+        let (first, second) = x.split_at_mut(mid_point);
+
+        if config.parallel_sort(mid_point, depth) {
+            rayon::join(
+                || do_sort(first, !forward, isa, config, depth + 1),
+                || do_sort(second, forward, isa, config, depth + 1),
+            );
+        } else {
+            do_sort(first, !forward, isa, config, depth);
+            do_sort(second, forward, isa, config, depth);
+        }
+
+        sub_sort(x, forward, isa, config, depth);
+    }
+}
+
+fn sub_sort<T: SimdElement>(
+    x: &mut [T],
+    forward: bool,
+    isa: Isa,
+    config: &ParallelSortConfig,
+    depth: usize,
+) {
+    if x.len() > 1 {
+        compare_and_swap(x, forward, isa);
+        let mid_point = x.len().next_power_of_two() / 2;
+        let (first, second) = x.split_at_mut(mid_point);
+
+        if config.parallel_sub_sort(mid_point, depth) {
+            rayon::join(
+                || sub_sort(first, forward, isa, config, depth + 1),
+                || sub_sort(second, forward, isa, config, depth + 1),
+            );
+        } else {
+            sub_sort(first, forward, isa, config, depth);
+            sub_sort(second, forward, isa, config, depth);
+        }
+    }
+}
+
+fn compare_and_swap<T: SimdElement>(x: &mut [T], forward: bool, isa: Isa) {
+    let mid_point = x.len().next_power_of_two() / 2;
+    let len = x.len() - mid_point;
+    // 前半の先頭 len 要素と後半（ちょうど len 要素）を、レーンごとにまとめて比較する
+    let (first, second) = x.split_at_mut(mid_point);
+    T::compare_and_swap_lanes(&mut first[..len], second, forward, isa);
+}
+
+// スカラー版。forward が true なら a > b のとき、false なら a < b のときに交換する
+// f32 の NaN はどちらの比較も false になるので交換されない
+fn compare_and_swap_scalar<T: Copy + PartialOrd>(a: &mut [T], b: &mut [T], forward: bool) {
+    for (a, b) in a.iter_mut().zip(b.iter_mut()) {
+        if (forward && *a > *b) || (!forward && *a < *b) {
+            std::mem::swap(a, b);
+        }
+    }
+}
+
+// 整数型は min/max 命令で比較と交換をまとめて行える
+macro_rules! impl_simd_integer {
+    ($t:ty, $avx2:ident, $sse41:ident, $min256:ident, $max256:ident, $min128:ident, $max128:ident) => {
+        impl SimdElement for $t {
+            fn compare_and_swap_lanes(a: &mut [Self], b: &mut [Self], forward: bool, isa: Isa) {
+                debug_assert_eq!(a.len(), b.len());
+                match isa {
+                    // Isa::detect で命令セットが使えることを確認済み
+                    #[cfg(target_arch = "x86_64")]
+                    Isa::Avx2 => unsafe { $avx2(a, b, forward) },
+                    #[cfg(target_arch = "x86_64")]
+                    Isa::Sse41 => unsafe { $sse41(a, b, forward) },
+                    _ => compare_and_swap_scalar(a, b, forward),
+                }
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        #[target_feature(enable = "avx2")]
+        unsafe fn $avx2(a: &mut [$t], b: &mut [$t], forward: bool) {
+            // 256ビットのレジスタには 32ビットの要素が 8個入る
+            let chunks = a.len() / 8;
+            for i in 0..chunks {
+                let pa = a.as_mut_ptr().add(i * 8) as *mut __m256i;
+                let pb = b.as_mut_ptr().add(i * 8) as *mut __m256i;
+                let va = _mm256_loadu_si256(pa);
+                let vb = _mm256_loadu_si256(pb);
+                let (lo, hi) = ($min256(va, vb), $max256(va, vb));
+                if forward {
+                    _mm256_storeu_si256(pa, lo);
+                    _mm256_storeu_si256(pb, hi);
+                } else {
+                    _mm256_storeu_si256(pa, hi);
+                    _mm256_storeu_si256(pb, lo);
+                }
+            }
+            // 8個に満たない残りはスカラー版で処理する
+            compare_and_swap_scalar(&mut a[chunks * 8..], &mut b[chunks * 8..], forward);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        #[target_feature(enable = "sse4.1")]
+        unsafe fn $sse41(a: &mut [$t], b: &mut [$t], forward: bool) {
+            // 128ビットのレジスタには 32ビットの要素が 4個入る
+            let chunks = a.len() / 4;
+            for i in 0..chunks {
+                let pa = a.as_mut_ptr().add(i * 4) as *mut __m128i;
+                let pb = b.as_mut_ptr().add(i * 4) as *mut __m128i;
+                let va = _mm_loadu_si128(pa);
+                let vb = _mm_loadu_si128(pb);
+                let (lo, hi) = ($min128(va, vb), $max128(va, vb));
+                if forward {
+                    _mm_storeu_si128(pa, lo);
+                    _mm_storeu_si128(pb, hi);
+                } else {
+                    _mm_storeu_si128(pa, hi);
+                    _mm_storeu_si128(pb, lo);
+                }
+            }
+            compare_and_swap_scalar(&mut a[chunks * 4..], &mut b[chunks * 4..], forward);
+        }
+    };
+}
+
+impl_simd_integer!(
+    u32,
+    compare_and_swap_u32_avx2,
+    compare_and_swap_u32_sse41,
+    _mm256_min_epu32,
+    _mm256_max_epu32,
+    _mm_min_epu32,
+    _mm_max_epu32
+);
+impl_simd_integer!(
+    i32,
+    compare_and_swap_i32_avx2,
+    compare_and_swap_i32_sse41,
+    _mm256_min_epi32,
+    _mm256_max_epi32,
+    _mm_min_epi32,
+    _mm_max_epi32
+);
+
+// f32 の min/max 命令は NaN や -0.0 と +0.0 の組で片方の値を複製してしまうので、
+// 比較結果のマスクで2つの値を入れ替える（blendv）ことで、要素が失われないようにする
+impl SimdElement for f32 {
+    fn compare_and_swap_lanes(a: &mut [Self], b: &mut [Self], forward: bool, isa: Isa) {
+        debug_assert_eq!(a.len(), b.len());
+        match isa {
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx2 => unsafe { compare_and_swap_f32_avx2(a, b, forward) },
+            #[cfg(target_arch = "x86_64")]
+            Isa::Sse41 => unsafe { compare_and_swap_f32_sse41(a, b, forward) },
+            _ => compare_and_swap_scalar(a, b, forward),
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn compare_and_swap_f32_avx2(a: &mut [f32], b: &mut [f32], forward: bool) {
+    let chunks = a.len() / 8;
+    for i in 0..chunks {
+        let pa = a.as_mut_ptr().add(i * 8);
+        let pb = b.as_mut_ptr().add(i * 8);
+        let va = _mm256_loadu_ps(pa);
+        let vb = _mm256_loadu_ps(pb);
+        // 交換が必要なレーンのビットがすべて 1 になる。NaN との比較は常に 0
+        let mask = if forward {
+            _mm256_cmp_ps(va, vb, _CMP_GT_OQ)
+        } else {
+            _mm256_cmp_ps(va, vb, _CMP_LT_OQ)
+        };
+        _mm256_storeu_ps(pa, _mm256_blendv_ps(va, vb, mask));
+        _mm256_storeu_ps(pb, _mm256_blendv_ps(vb, va, mask));
+    }
+    compare_and_swap_scalar(&mut a[chunks * 8..], &mut b[chunks * 8..], forward);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.1")]
+unsafe fn compare_and_swap_f32_sse41(a: &mut [f32], b: &mut [f32], forward: bool) {
+    let chunks = a.len() / 4;
+    for i in 0..chunks {
+        let pa = a.as_mut_ptr().add(i * 4);
+        let pb = b.as_mut_ptr().add(i * 4);
+        let va = _mm_loadu_ps(pa);
+        let vb = _mm_loadu_ps(pb);
+        let mask = if forward {
+            _mm_cmpgt_ps(va, vb)
+        } else {
+            _mm_cmplt_ps(va, vb)
+        };
+        _mm_storeu_ps(pa, _mm_blendv_ps(va, vb, mask));
+        _mm_storeu_ps(pb, _mm_blendv_ps(vb, va, mask));
+    }
+    compare_and_swap_scalar(&mut a[chunks * 4..], &mut b[chunks * 4..], forward);
+}
+
+// このモジュールは cargo test を実行したときのみコンパイルされる
+#[cfg(test)]
+mod tests {
+    use super::{
+        compare_and_swap_scalar, downcast_slice_mut, sort, sort_f32_total_order, sort_with_config,
+        Isa, SimdElement,
+    };
+    use crate::fourth::ParallelSortConfig;
+    use crate::utils::{is_sorted_ascending, is_sorted_descending, new_u32_vec};
+    use crate::SortOrder::*;
+
+    // 実行環境で使える命令セットをすべて列挙する
+    fn available_isas() -> Vec<Isa> {
+        let mut isas = vec![Isa::Scalar];
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("sse4.1") {
+                isas.push(Isa::Sse41);
+            }
+            if is_x86_feature_detected!("avx2") {
+                isas.push(Isa::Avx2);
+            }
+        }
+        isas
+    }
+
+    #[test]
+    fn compare_and_swap_lanes_matches_scalar() {
+        let x: Vec<i32> = new_u32_vec(37).into_iter().map(|n| n as i32).collect();
+        for isa in available_isas() {
+            for &forward in &[true, false] {
+                let (mut a, mut b) = (x[..18].to_vec(), x[19..].to_vec());
+                let (mut expected_a, mut expected_b) = (a.clone(), b.clone());
+                i32::compare_and_swap_lanes(&mut a, &mut b, forward, isa);
+                compare_and_swap_scalar(&mut expected_a, &mut expected_b, forward);
+                assert_eq!((a, b), (expected_a, expected_b), "{:?}", isa);
+            }
+        }
+    }
+
+    #[test]
+    fn sort_u32() {
+        for len in 0..=300 {
+            let mut x = new_u32_vec(len);
+            assert_eq!(sort(&mut x, &Ascending), Ok(()));
+            assert!(is_sorted_ascending(&x));
+
+            let mut x = new_u32_vec(len);
+            assert_eq!(sort(&mut x, &Descending), Ok(()));
+            assert!(is_sorted_descending(&x));
+        }
+    }
+
+    #[test]
+    fn sort_i32() {
+        let mut x = vec![10, -30, 11, 20, 4, -330, 21, 110, 0];
+        assert_eq!(sort(&mut x, &Ascending), Ok(()));
+        assert_eq!(x, vec![-330, -30, 0, 4, 10, 11, 20, 21, 110]);
+    }
+
+    #[test]
+    fn sort_f32() {
+        let mut x = vec![20.0, -30.0, 11.0, 10.0, 0.5, -0.0, 0.0, 110.0, 3.25];
+        assert_eq!(sort(&mut x, &Descending), Ok(()));
+        assert_eq!(x, vec![110.0, 20.0, 11.0, 10.0, 3.25, 0.5, 0.0, 0.0, -30.0]);
+        // -0.0 と +0.0 は等しいとみなされるが、どちらも失われない
+        assert_eq!(x.iter().filter(|v| v.is_sign_negative()).count(), 2);
+    }
+
+    #[test]
+    fn sort_f32_nan_keeps_elements() {
+        let mut x = vec![2.0, f32::NAN, 1.0, 3.0, f32::NAN];
+        assert_eq!(sort(&mut x, &Ascending), Ok(()));
+        assert_eq!(x.iter().filter(|v| v.is_nan()).count(), 2);
+    }
+
+    #[test]
+    fn sort_f32_total_order_matches_total_cmp() {
+        let mut x: Vec<f32> = new_u32_vec(10_000)
+            .into_iter()
+            .map(|v| f32::from_bits(v.rotate_left(7)))
+            .collect();
+        x.extend_from_slice(&[-0.0, 0.0, f32::NAN, -f32::NAN, f32::INFINITY]);
+        for &order in &[Ascending, Descending] {
+            let mut actual = x.clone();
+            sort_f32_total_order(&mut actual, &order, &ParallelSortConfig::default());
+            let mut expected = x.clone();
+            expected.sort_by(|a, b| match order {
+                Ascending => a.total_cmp(b),
+                Descending => b.total_cmp(a),
+            });
+            let bits = |v: &[f32]| v.iter().map(|f| f.to_bits()).collect::<Vec<_>>();
+            assert_eq!(bits(&actual), bits(&expected));
+        }
+    }
+
+    #[test]
+    fn downcast_only_same_type() {
+        let mut x = vec![1u32, 2];
+        assert!(downcast_slice_mut::<u32, u32>(&mut x).is_some());
+        assert!(downcast_slice_mut::<u32, i32>(&mut x).is_none());
+        // 借用を含む型も渡せる
+        let s = String::from("a");
+        let mut x = vec![s.as_str()];
+        assert!(downcast_slice_mut::<&str, u32>(&mut x).is_none());
+    }
+
+    #[test]
+    fn sort_u32_large_parallel() {
+        let mut x = new_u32_vec(100_000);
+        sort_with_config(&mut x, &Ascending, &ParallelSortConfig::default());
+        assert!(is_sorted_ascending(&x));
+    }
+}
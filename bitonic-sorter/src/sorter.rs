@@ -0,0 +1,297 @@
+// 順次ソート（third）と並列ソート（fourth）を同じインターフェイスで扱うためのトレイト
+// アプリケーション側はジェネリクス（S: Sorter<T>）でもトレイトオブジェクト（&dyn Sorter<T>）
+// でもソーターを受け取れるので、アルゴリズムを差し替えやすくなる
+use super::fourth::{self, ParallelSortConfig};
+use super::simd::{self, SimdElement};
+use super::stats::SortStats;
+use super::{odd_even, third, SortError, SortOrder};
+use std::cmp::Ordering;
+
+pub trait Sorter<T> {
+    // comparator はトレイトオブジェクトとして受け取る
+    // ジェネリクスにするとトレイトオブジェクトとして使えなくなるため
+    // 並列ソーターでも使えるように Sync 境界をつけておく
+    fn sort_by(
+        &self,
+        x: &mut [T],
+        comparator: &(dyn Fn(&T, &T) -> Ordering + Sync),
+    ) -> Result<(), SortError>;
+
+    // デフォルト実装。sort_by を呼ぶだけなので、実装する側は sort_by だけ書けばよい
+    // ただし比較のたびにトレイトオブジェクト経由の呼び出しになるので、このモジュールの
+    // ソーターは各モジュールの sort を直接呼ぶように上書きしている
+    fn sort(&self, x: &mut [T], order: &SortOrder) -> Result<(), SortError>
+    where
+        T: Ord,
+    {
+        match *order {
+            SortOrder::Ascending => self.sort_by(x, &|a, b| a.cmp(b)),
+            SortOrder::Descending => self.sort_by(x, &|a, b| b.cmp(a)),
+        }
+    }
+
+    // キーの型 K をジェネリクスで受け取るので、トレイトオブジェクトからは呼べない
+    // （Self: Sized を付けてトレイトオブジェクトの対象から外している）
+    fn sort_by_key<K, F>(&self, x: &mut [T], key: &F) -> Result<(), SortError>
+    where
+        K: Ord,
+        F: Sync + Fn(&T) -> K,
+        Self: Sized,
+    {
+        self.sort_by(x, &|a, b| key(a).cmp(&key(b)))
+    }
+}
+
+// 第三段階の順次ソート（third::sort_by）を使うソーター
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SeqSorter;
+
+impl<T> Sorter<T> for SeqSorter {
+    fn sort_by(
+        &self,
+        x: &mut [T],
+        comparator: &(dyn Fn(&T, &T) -> Ordering + Sync),
+    ) -> Result<(), SortError> {
+        third::sort_by(x, &comparator)
+    }
+
+    fn sort(&self, x: &mut [T], order: &SortOrder) -> Result<(), SortError>
+    where
+        T: Ord,
+    {
+        third::sort(x, order)
+    }
+}
+
+impl SeqSorter {
+    // sort_by と同じようにソートし、比較や交換の回数を返す（third::sort_by_with_stats）
+    pub fn sort_by_with_stats<T>(
+        &self,
+        x: &mut [T],
+        comparator: &(dyn Fn(&T, &T) -> Ordering + Sync),
+    ) -> Result<SortStats, SortError> {
+        third::sort_by_with_stats(x, &comparator)
+    }
+}
+
+// 第四段階の rayon による並列ソート（fourth::sort_by）を使うソーター
+// 要素をスレッド間で受け渡すので T には Send 境界が必要
+#[derive(Debug, Default, Clone)]
+pub struct ParSorter {
+    // 並列化の閾値やスレッドプールの設定
+    pub config: ParallelSortConfig,
+}
+
+impl ParSorter {
+    pub fn new(config: ParallelSortConfig) -> Self {
+        Self { config }
+    }
+
+    // sort_by と同じ設定でソートし、比較や交換の回数、タスクを分けた回数を返す
+    pub fn sort_by_with_stats<T: Send>(
+        &self,
+        x: &mut [T],
+        comparator: &(dyn Fn(&T, &T) -> Ordering + Sync),
+    ) -> Result<SortStats, SortError> {
+        fourth::sort_by_with_stats(x, &comparator, &self.config)
+    }
+}
+
+impl<T: Send> Sorter<T> for ParSorter {
+    fn sort_by(
+        &self,
+        x: &mut [T],
+        comparator: &(dyn Fn(&T, &T) -> Ordering + Sync),
+    ) -> Result<(), SortError> {
+        fourth::sort_by_with_config(x, &comparator, &self.config)
+    }
+
+    // u32 と i32 は SIMD 版に切り替わる
+    fn sort(&self, x: &mut [T], order: &SortOrder) -> Result<(), SortError>
+    where
+        T: Ord,
+    {
+        fourth::sort_with_config(x, order, &self.config)
+    }
+}
+
+// Batcher の奇偶マージソート（odd_even::sort_by）を使う順次ソーター
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OddEvenSorter;
+
+impl<T> Sorter<T> for OddEvenSorter {
+    fn sort_by(
+        &self,
+        x: &mut [T],
+        comparator: &(dyn Fn(&T, &T) -> Ordering + Sync),
+    ) -> Result<(), SortError> {
+        odd_even::sort_by(x, &comparator)
+    }
+
+    fn sort(&self, x: &mut [T], order: &SortOrder) -> Result<(), SortError>
+    where
+        T: Ord,
+    {
+        odd_even::sort(x, order)
+    }
+}
+
+// Batcher の奇偶マージソートの並列版（odd_even::par_sort_by）を使うソーター
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ParOddEvenSorter;
+
+impl<T: Send> Sorter<T> for ParOddEvenSorter {
+    fn sort_by(
+        &self,
+        x: &mut [T],
+        comparator: &(dyn Fn(&T, &T) -> Ordering + Sync),
+    ) -> Result<(), SortError> {
+        odd_even::par_sort_by(x, &comparator)
+    }
+
+    fn sort(&self, x: &mut [T], order: &SortOrder) -> Result<(), SortError>
+    where
+        T: Ord,
+    {
+        odd_even::par_sort(x, order)
+    }
+}
+
+// u32、i32、f32 に対して SIMD 命令を使う順次ソーター（simd::sort）
+// sort だけを SIMD 版で上書きし、sort_by は third::sort_by にフォールバックする
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SimdSorter;
+
+impl<T: SimdElement> Sorter<T> for SimdSorter {
+    fn sort_by(
+        &self,
+        x: &mut [T],
+        comparator: &(dyn Fn(&T, &T) -> Ordering + Sync),
+    ) -> Result<(), SortError> {
+        third::sort_by(x, &comparator)
+    }
+
+    fn sort(&self, x: &mut [T], order: &SortOrder) -> Result<(), SortError>
+    where
+        T: Ord,
+    {
+        simd::sort(x, order)
+    }
+}
+
+// このモジュールは cargo test を実行したときのみコンパイルされる
+#[cfg(test)]
+mod tests {
+    use super::{OddEvenSorter, ParOddEvenSorter, ParSorter, SeqSorter, SimdSorter, Sorter};
+    use crate::fourth::{BaseCase, Hybrid, ParallelSortConfig};
+    use crate::stats::StatsCounter;
+    use crate::utils::{is_sorted_ascending, is_sorted_descending, new_u32_vec};
+    use crate::SortOrder::*;
+    use std::sync::Arc;
+
+    // ジェネリクスでソーターを受け取る
+    fn sort_with<S: Sorter<u32>>(sorter: &S, len: usize) {
+        let mut x = new_u32_vec(len);
+        assert_eq!(sorter.sort(&mut x, &Ascending), Ok(()));
+        assert!(is_sorted_ascending(&x));
+    }
+
+    #[test]
+    fn sort_with_generics() {
+        sort_with(&SeqSorter, 1000);
+        sort_with(&ParSorter::default(), 10_000);
+        sort_with(&SimdSorter, 10_000);
+        sort_with(&OddEvenSorter, 1000);
+        sort_with(&ParOddEvenSorter, 10_000);
+    }
+
+    #[test]
+    fn sort_with_trait_objects() {
+        // トレイトオブジェクトにすれば、異なるソーターをひとつのベクタにまとめられる
+        let sorters: Vec<Box<dyn Sorter<u32>>> = vec![
+            Box::new(SeqSorter),
+            Box::new(ParSorter::default()),
+            Box::new(SimdSorter),
+            Box::new(OddEvenSorter),
+            Box::new(ParOddEvenSorter),
+        ];
+        for sorter in sorters {
+            let mut x = new_u32_vec(10_000);
+            assert_eq!(sorter.sort(&mut x, &Descending), Ok(()));
+            assert!(is_sorted_descending(&x));
+
+            let mut x = new_u32_vec(1000);
+            assert_eq!(sorter.sort_by(&mut x, &|a, b| a.cmp(b)), Ok(()));
+            assert!(is_sorted_ascending(&x));
+        }
+    }
+
+    #[test]
+    fn sort_by_key() {
+        let mut x = vec!["Rust", "is", "and", "memory-efficient"];
+        assert_eq!(SeqSorter.sort_by_key(&mut x, &|s: &&str| s.len()), Ok(()));
+        assert_eq!(x, vec!["is", "and", "Rust", "memory-efficient"]);
+
+        let mut x = vec!["Rust", "is", "fast", "and", "memory-efficient"];
+        assert_eq!(
+            ParSorter::default().sort_by_key(&mut x, &|s: &&str| s.to_lowercase()),
+            Ok(())
+        );
+        assert_eq!(x, vec!["and", "fast", "is", "memory-efficient", "Rust"]);
+    }
+
+    #[test]
+    fn sort_with_stats() {
+        let mut x = new_u32_vec(1 << 12);
+        let seq = SeqSorter
+            .sort_by_with_stats(&mut x, &|a, b| a.cmp(b))
+            .unwrap();
+        assert!(is_sorted_ascending(&x));
+        // 要素数 2^k のバイトニックソートの比較器の数は 2^(k-1) * k(k+1)/2
+        assert_eq!(seq.comparisons, (1 << 11) * 12 * 13 / 2);
+        assert!(seq.swaps > 0 && seq.swaps < seq.comparisons);
+        assert_eq!(seq.task_splits, 0);
+
+        // 比較器は同じで、並列化した分だけタスクが分かれる
+        let sorter = ParSorter::new(ParallelSortConfig {
+            sort_threshold: 256,
+            sub_sort_threshold: 256,
+            ..Default::default()
+        });
+        let mut x = new_u32_vec(1 << 12);
+        let par = sorter.sort_by_with_stats(&mut x, &|a, b| a.cmp(b)).unwrap();
+        assert!(is_sorted_ascending(&x));
+        assert_eq!(par.comparisons, seq.comparisons);
+        assert_eq!(par.swaps, seq.swaps);
+        assert_eq!(par.stages, seq.stages);
+        assert!(par.task_splits > 0);
+    }
+
+    #[test]
+    fn stats_counter_accumulates() {
+        // 同じカウンタを設定した config でソートすると、回数が積み上がる
+        let counter = Arc::new(StatsCounter::new());
+        let config = ParallelSortConfig {
+            stats: Some(Arc::clone(&counter)),
+            hybrid: Some(Hybrid {
+                block_size: 8,
+                base_case: BaseCase::InsertionSort,
+            }),
+            ..Default::default()
+        };
+        let sorter = ParSorter::new(config);
+        for _ in 0..3 {
+            let mut x = new_u32_vec(1000);
+            sorter.sort(&mut x, &Descending).unwrap();
+            assert!(is_sorted_descending(&x));
+        }
+        let stats = counter.snapshot();
+
+        let mut x = new_u32_vec(1000);
+        let once = sorter.sort_by_with_stats(&mut x, &|a, b| b.cmp(a)).unwrap();
+        assert_eq!(stats.comparisons, once.comparisons * 3);
+        assert_eq!(stats.swaps, once.swaps * 3);
+        // sort_by_with_stats の回数は config のカウンタには記録されない
+        assert_eq!(counter.snapshot(), stats);
+    }
+}
@@ -0,0 +1,100 @@
+// アルゴリズムの研究用に、ソート中の比較や交換の回数を数える
+// ParallelSortConfig::stats に StatsCounter を設定すると、fourth の do_sort と sub_sort が
+// 回数を記録する。複数のスレッドから同時に記録されるので、カウンタはアトミック変数にする
+// third では、トレースと同じ Observer として compare_and_swap に渡して数える（LocalStats）
+use super::trace::Observer;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// ソート1回分（または StatsCounter に記録したすべてのソート）の統計
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SortStats {
+    // comparator を呼んだ回数
+    pub comparisons: usize,
+    // 要素を交換した回数（ハイブリッドモードで BaseCase::SortUnstable が行った交換は含まない）
+    pub swaps: usize,
+    // マージのステップ（前半と後半の対応する要素を比較する compare_and_swap）を実行した回数
+    pub stages: usize,
+    // rayon::join でタスクを分けた回数
+    pub task_splits: usize,
+}
+
+// SortStats のカウンタ。スレッド間で共有するので Arc に入れて使う
+#[derive(Debug, Default)]
+pub struct StatsCounter {
+    comparisons: AtomicUsize,
+    swaps: AtomicUsize,
+    stages: AtomicUsize,
+    task_splits: AtomicUsize,
+}
+
+impl StatsCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // これまでに記録した回数を返す
+    // ソートの実行中に呼ぶと、各カウンタは少しずつ異なる時点の値になる
+    pub fn snapshot(&self) -> SortStats {
+        SortStats {
+            comparisons: self.comparisons.load(Ordering::Relaxed),
+            swaps: self.swaps.load(Ordering::Relaxed),
+            stages: self.stages.load(Ordering::Relaxed),
+            task_splits: self.task_splits.load(Ordering::Relaxed),
+        }
+    }
+
+    // 回数は合計さえ正しければよく、ほかのメモリ操作との順序は関係ないので Relaxed で足りる
+    // 比較のたびにアトミック変数を更新すると遅いので、呼び出し側でまとめてから足す
+    pub(crate) fn add_comparisons(&self, n: usize) {
+        self.comparisons.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_swaps(&self, n: usize) {
+        self.swaps.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_stage(&self) {
+        self.stages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_task_split(&self) {
+        self.task_splits.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// third の compare_and_swap に Observer として渡し、比較と交換、マージのステップの回数を数える
+// third は1つのスレッドでソートするので、アトミック変数ではなく Cell で数え、最後に SortStats にする
+// third はタスクを分けないので、task_splits は 0 のまま
+#[derive(Debug, Default)]
+pub(crate) struct LocalStats {
+    comparisons: Cell<usize>,
+    swaps: Cell<usize>,
+    stages: Cell<usize>,
+}
+
+impl LocalStats {
+    pub(crate) fn into_stats(self) -> SortStats {
+        SortStats {
+            comparisons: self.comparisons.into_inner(),
+            swaps: self.swaps.into_inner(),
+            stages: self.stages.into_inner(),
+            task_splits: 0,
+        }
+    }
+}
+
+impl<T> Observer<T> for &LocalStats {
+    fn shift(self, _offset: usize) -> Self {
+        self
+    }
+
+    fn stage(self) {
+        self.stages.set(self.stages.get() + 1);
+    }
+
+    fn compared(self, _i: usize, _j: usize, _a: &T, _b: &T, swapped: bool) {
+        self.comparisons.set(self.comparisons.get() + 1);
+        self.swaps.set(self.swaps.get() + swapped as usize);
+    }
+}
@@ -5,36 +5,246 @@
 //   u32 型は32ビット符号なし整数
 //   [u32] 型はu32のスライス（現時点ではスライスは1次元の配列と考えてよい）
 // 型パラメータＴにトレイト境界Ord（全順序）を追加する
-use super::SortOrder;
+use super::float::{Float, FloatOrder};
+use super::stats::{LocalStats, SortStats};
+use super::trace::{Observer, Trace};
+use super::{catching, SortError, SortOrder};
+use crate::utils::apply_permutation;
 use std::cmp::Ordering;
+use std::mem;
 
-pub fn sort_by<T, F>(x: &mut [T], comparator: &F) -> Result<(), String>
+pub fn sort_by<T, F>(x: &mut [T], comparator: &F) -> Result<(), SortError>
 where
     F: Fn(&T, &T) -> Ordering,
 {
-    if x.len().is_power_of_two() {
-        do_sort(x, true, comparator);
-        Ok(())
-    } else {
-        Err(format!(
-            "The length of x is not a power of two. (x.len(): {})",
-            x.len()
-        ))
+    // 要素数が2のべき乗でなくてもソートできるので、常に Ok を返す
+    // comparator のパニックは捕捉せずに、そのまま呼び出し元に伝える
+    do_sort(x, true, comparator, ());
+    Ok(())
+}
+
+// sort_by と同じだが、comparator のパニックを捕捉して Err(SortError::ComparatorPanicked) を返す
+pub fn sort_by_catching<T, F>(x: &mut [T], comparator: &F) -> Result<(), SortError>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    catching(|| sort_by(x, comparator))
+}
+
+// 安定ソート。comparator が Equal を返す要素どうしは、元の順序が保たれる
+// バイトニックソートは安定ではないので、argsort_by で得たインデックスの順に
+// x の要素を並べ替える
+pub fn sort_by_stable<T, F>(x: &mut [T], comparator: &F) -> Result<(), SortError>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    let indices = argsort_by(x, comparator)?;
+    apply_permutation(x, &indices);
+    Ok(())
+}
+
+// x をソートしたときに i 番目に来る要素の元のインデックスを、i 番目に持つベクタを返す
+// x 自体は並べ替えない。要素の代わりにインデックスの列をソートし、比較結果が Equal なら
+// 元の位置（インデックス）の小さい方を前にするので、安定ソートと同じ順序になる
+pub fn argsort_by<T, F>(x: &[T], comparator: &F) -> Result<Vec<usize>, SortError>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    let mut indices: Vec<usize> = (0..x.len()).collect();
+    sort_by(&mut indices, &|&i, &j| {
+        comparator(&x[i], &x[j]).then_with(|| i.cmp(&j))
+    })?;
+    Ok(indices)
+}
+
+pub fn argsort<T: Ord>(x: &[T], order: &SortOrder) -> Result<Vec<usize>, SortError> {
+    match *order {
+        SortOrder::Ascending => argsort_by(x, &|a, b| a.cmp(b)),
+        SortOrder::Descending => argsort_by(x, &|a, b| b.cmp(a)),
+    }
+}
+
+// 要素からキーを取り出して比較する。キーは比較のたびに計算される
+pub fn sort_by_key<T, K, F>(x: &mut [T], key: &F) -> Result<(), SortError>
+where
+    K: Ord,
+    F: Fn(&T) -> K,
+{
+    sort_by(x, &|a, b| key(a).cmp(&key(b)))
+}
+
+// キーの計算が重い場合（たとえば文字列の小文字化）に使う
+// キーは要素ごとに一度だけ計算し、(キー, 元の位置) の組をソートしてから x を並べ替える
+// 元の位置も比較するので、std の sort_by_cached_key と同じく安定ソートになる
+pub fn sort_by_cached_key<T, K, F>(x: &mut [T], key: &F) -> Result<(), SortError>
+where
+    K: Ord,
+    F: Fn(&T) -> K,
+{
+    let mut keys: Vec<(K, usize)> = x.iter().map(key).zip(0..).collect();
+    sort_by(&mut keys, &|a, b| a.cmp(b))?;
+    let indices: Vec<usize> = keys.into_iter().map(|(_, i)| i).collect();
+    apply_permutation(x, &indices);
+    Ok(())
+}
+
+// キーのスライスと値のスライスを、キーの順序で一緒に並べ替える
+// （構造体の配列ではなく、配列の構造体でデータを持っている場合に使う）
+// compare_and_swap でキーを交換したときに、同じ位置の値も交換する
+pub fn sort_pairs<K: Ord, V>(
+    keys: &mut [K],
+    values: &mut [V],
+    order: &SortOrder,
+) -> Result<(), SortError> {
+    if keys.len() != values.len() {
+        return Err(SortError::LengthMismatch {
+            keys: keys.len(),
+            values: values.len(),
+        });
     }
+    let forward = match *order {
+        SortOrder::Ascending => true,
+        SortOrder::Descending => false,
+    };
+    do_sort_pairs(keys, values, forward);
+    Ok(())
 }
 
-pub fn sort<T: Ord>(x: &mut [T], order: &SortOrder) -> Result<(), String> {
+// ソート済みの x[..mid] と x[mid..] を、x 全体がソート済みになるようにマージする
+// 前半を反転すると x は「下がってから上がる」バイトニック列になるので、sub_sort だけでマージできる
+// 末尾のパディング（+∞）を足してもバイトニック列のままなので、前半と後半の長さは異なってよい
+// mid が x.len() より大きいときは、x を変更せずに Err(SortError::MidOutOfRange) を返す
+pub fn bitonic_merge_by<T, F>(x: &mut [T], mid: usize, comparator: &F) -> Result<(), SortError>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    if mid > x.len() {
+        return Err(SortError::MidOutOfRange { mid, len: x.len() });
+    }
+    x[..mid].reverse();
+    sub_sort(x, true, comparator, ());
+    Ok(())
+}
+
+// ソート済みのバッファの末尾にソート済みの列を追加したあと、全体をソートし直さずに済む
+pub fn bitonic_merge<T: Ord>(x: &mut [T], mid: usize, order: &SortOrder) -> Result<(), SortError> {
+    match *order {
+        SortOrder::Ascending => bitonic_merge_by(x, mid, &|a, b| a.cmp(b)),
+        SortOrder::Descending => bitonic_merge_by(x, mid, &|a, b| b.cmp(a)),
+    }
+}
+
+// order の順にソート済みの a と b をマージして out に書き込む。a と b は変更しない
+// out の要素数は a.len() + b.len() でなければならない
+pub fn merge_sorted<T: Ord + Clone>(
+    a: &[T],
+    b: &[T],
+    out: &mut [T],
+    order: &SortOrder,
+) -> Result<(), SortError> {
+    if out.len() != a.len() + b.len() {
+        return Err(SortError::OutputLengthMismatch {
+            expected: a.len() + b.len(),
+            actual: out.len(),
+        });
+    }
+    let (first, second) = out.split_at_mut(a.len());
+    first.clone_from_slice(a);
+    second.clone_from_slice(b);
+    bitonic_merge(out, a.len(), order)
+}
+
+// comparator の順で先頭から k 個の要素を選び、ソートして x[..k] に置く。x[k..] の順序は不定
+// k が x.len() 以上なら x 全体をソートする
+pub fn partial_sort_by<T, F>(x: &mut [T], k: usize, comparator: &F) -> Result<(), SortError>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    do_partial_sort(x, k, comparator);
+    Ok(())
+}
+
+pub fn partial_sort<T: Ord>(x: &mut [T], k: usize, order: &SortOrder) -> Result<(), SortError> {
+    match *order {
+        SortOrder::Ascending => partial_sort_by(x, k, &|a, b| a.cmp(b)),
+        SortOrder::Descending => partial_sort_by(x, k, &|a, b| b.cmp(a)),
+    }
+}
+
+// order の順で先頭から k 個の要素（Ascending なら最小の k 個）を、ソートしたベクタで返す
+// x は変更しない
+pub fn top_k<T: Ord + Clone>(x: &[T], k: usize, order: &SortOrder) -> Result<Vec<T>, SortError> {
+    let mut x = x.to_vec();
+    partial_sort(&mut x, k, order)?;
+    x.truncate(k);
+    Ok(x)
+}
+
+pub fn sort<T: Ord>(x: &mut [T], order: &SortOrder) -> Result<(), SortError> {
     // do_sort を呼ぶ代わりに、sort_by を呼ぶようにする
-    // is_power_of_two は sort_by が呼ぶので、ここからは削除した
     match *order {
         SortOrder::Ascending => sort_by(x, &|a, b| a.cmp(b)),
         SortOrder::Descending => sort_by(x, &|a, b| b.cmp(a)),
     }
 }
 
-fn do_sort<T, F>(x: &mut [T], forward: bool, comparator: &F)
+// 失敗するかもしれない comparator でソートする
+// comparator が Err を返したら、それ以降は比較せずにそのエラーを返す
+// 要素は swap でしか動かさないので、x は入力の要素を並べ替えたもののままになる
+pub fn try_sort_by<T, E, F>(x: &mut [T], comparator: &F) -> Result<(), E>
+where
+    F: Fn(&T, &T) -> Result<Ordering, E>,
+{
+    try_do_sort(x, true, comparator)
+}
+
+// f32 や f64 のスライスを、float_order に従って order の順にソートする
+// NaN が含まれていてもパニックしない
+pub fn sort_floats<T: Float>(
+    x: &mut [T],
+    order: &SortOrder,
+    float_order: &FloatOrder,
+) -> Result<(), SortError> {
+    sort_by(x, &|a, b| float_order.compare(a, b, order))
+}
+
+// sort_by と同じようにソートし、compare_and_swap で比較した要素のインデックスと値、
+// 交換したかどうかを実行した順に記録したトレースを返す
+pub fn sort_by_traced<T: Clone, F>(x: &mut [T], comparator: &F) -> Result<Trace<T>, SortError>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    Trace::record(x, |x, tracer| {
+        do_sort(x, true, comparator, tracer);
+        Ok(())
+    })
+}
+
+// sort_by と同じようにソートし、比較や交換の回数を返す
+pub fn sort_by_with_stats<T, F>(x: &mut [T], comparator: &F) -> Result<SortStats, SortError>
 where
     F: Fn(&T, &T) -> Ordering,
+{
+    let stats = LocalStats::default();
+    do_sort(x, true, comparator, &stats);
+    Ok(stats.into_stats())
+}
+
+pub fn sort_traced<T: Ord + Clone>(x: &mut [T], order: &SortOrder) -> Result<Trace<T>, SortError> {
+    match *order {
+        SortOrder::Ascending => sort_by_traced(x, &|a, b| a.cmp(b)),
+        SortOrder::Descending => sort_by_traced(x, &|a, b| b.cmp(a)),
+    }
+}
+
+// 要素数が2のべき乗でない場合は、末尾に仮想的な要素（パディング）があるものとして扱う
+// パディングは forward が true なら +∞、false なら -∞ とみなせるので、比較相手が
+// パディングになる比較器は何もしないのと同じになり、実際に配列を確保する必要はない
+// observer には比較するたびに通知する。通常のソートでは () を渡す
+pub(crate) fn do_sort<T, F, O>(x: &mut [T], forward: bool, comparator: &F, observer: O)
+where
+    F: Fn(&T, &T) -> Ordering,
+    O: Observer<T>,
 {
     // 未実装の意味。コンパイルは通るが、実行すると panic する
     // unimplemented!();
@@ -43,30 +253,45 @@ where
         let mid_point = x.len() / 2;
 
         // x をバイトニックソートする
-        // 第二引数が true のときは comparator で示される順序でソート
-        do_sort(&mut x[..mid_point], true, comparator);
-        // 第二引数が false のときは comparator とは逆順でソート
-        do_sort(&mut x[mid_point..], false, comparator);
+        // 前半は逆順、後半は forward の順でソートする
+        // こうするとパディングが常に後半の末尾に来るので、要素数が奇数でも
+        // 全体がバイトニック列になる
+        do_sort(&mut x[..mid_point], !forward, comparator, observer);
+        do_sort(
+            &mut x[mid_point..],
+            forward,
+            comparator,
+            observer.shift(mid_point),
+        );
 
-        sub_sort(x, forward, comparator);
+        sub_sort(x, forward, comparator, observer);
     }
 }
 
-fn sub_sort<T, F>(x: &mut [T], forward: bool, comparator: &F)
+pub(crate) fn sub_sort<T, F, O>(x: &mut [T], forward: bool, comparator: &F, observer: O)
 where
     F: Fn(&T, &T) -> Ordering,
+    O: Observer<T>,
 {
     if x.len() > 1 {
-        compare_and_swap(x, forward, comparator);
-        let mid_point = x.len() / 2;
-        sub_sort(&mut x[..mid_point], forward, comparator);
-        sub_sort(&mut x[mid_point..], forward, comparator);
+        compare_and_swap(x, forward, comparator, observer);
+        // x.len() 未満で最大の2のべき乗で分割する
+        // 2のべき乗の長さならちょうど半分になる
+        let mid_point = x.len().next_power_of_two() / 2;
+        sub_sort(&mut x[..mid_point], forward, comparator, observer);
+        sub_sort(
+            &mut x[mid_point..],
+            forward,
+            comparator,
+            observer.shift(mid_point),
+        );
     }
 }
 
-fn compare_and_swap<T, F>(x: &mut [T], forward: bool, comparator: &F)
+pub(crate) fn compare_and_swap<T, F, O>(x: &mut [T], forward: bool, comparator: &F, observer: O)
 where
     F: Fn(&T, &T) -> Ordering,
+    O: Observer<T>,
 {
     // 比較に先立ち forward(bool) を Orderingに変換しておく
     let swap_condition = if forward {
@@ -75,23 +300,164 @@ where
         Ordering::Less
     };
 
-    let mid_point = x.len() / 2;
+    let mid_point = x.len().next_power_of_two() / 2;
 
-    for i in 0..mid_point {
+    observer.stage();
+    // mid_point + i が x.len() 以上になる比較相手はパディングなので比較しない
+    for i in 0..(x.len() - mid_point) {
         // comparator クロージャで2要素を比較し、返されたOrderingのバリアントが
         // swap_condition とひとしいなら要素を交換する
-        if comparator(&x[i], &x[mid_point + i]) == swap_condition {
+        let swapped = comparator(&x[i], &x[mid_point + i]) == swap_condition;
+        observer.compared(i, mid_point + i, &x[i], &x[mid_point + i], swapped);
+        if swapped {
+            x.swap(i, mid_point + i)
+        }
+    }
+}
+
+// do_sort、sub_sort、compare_and_swap と同じ再帰で、comparator のエラーを ? で呼び出し元に返す
+fn try_do_sort<T, E, F>(x: &mut [T], forward: bool, comparator: &F) -> Result<(), E>
+where
+    F: Fn(&T, &T) -> Result<Ordering, E>,
+{
+    if x.len() > 1 {
+        let mid_point = x.len() / 2;
+        try_do_sort(&mut x[..mid_point], !forward, comparator)?;
+        try_do_sort(&mut x[mid_point..], forward, comparator)?;
+        try_sub_sort(x, forward, comparator)?;
+    }
+    Ok(())
+}
+
+fn try_sub_sort<T, E, F>(x: &mut [T], forward: bool, comparator: &F) -> Result<(), E>
+where
+    F: Fn(&T, &T) -> Result<Ordering, E>,
+{
+    if x.len() > 1 {
+        try_compare_and_swap(x, forward, comparator)?;
+        let mid_point = x.len().next_power_of_two() / 2;
+        try_sub_sort(&mut x[..mid_point], forward, comparator)?;
+        try_sub_sort(&mut x[mid_point..], forward, comparator)?;
+    }
+    Ok(())
+}
+
+fn try_compare_and_swap<T, E, F>(x: &mut [T], forward: bool, comparator: &F) -> Result<(), E>
+where
+    F: Fn(&T, &T) -> Result<Ordering, E>,
+{
+    let swap_condition = if forward {
+        Ordering::Greater
+    } else {
+        Ordering::Less
+    };
+    let mid_point = x.len().next_power_of_two() / 2;
+    for i in 0..(x.len() - mid_point) {
+        if comparator(&x[i], &x[mid_point + i])? == swap_condition {
             x.swap(i, mid_point + i)
         }
     }
+    Ok(())
+}
+
+// x を k 要素ずつのブロックに分けてソートし、先頭のブロックに小さい方の k 個を集めていく
+// 全体をソートしないので、比較の回数は O(n log² n) ではなく O(n log² k) になる
+fn do_partial_sort<T, F>(x: &mut [T], k: usize, comparator: &F)
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    if k == 0 {
+        return;
+    }
+    if k >= x.len() {
+        do_sort(x, true, comparator, ());
+        return;
+    }
+
+    // 先頭のブロックは逆順（降順）に保つ
+    let (best, rest) = x.split_at_mut(k);
+    do_sort(best, false, comparator, ());
+    for block in rest.chunks_mut(k) {
+        do_sort(block, true, comparator, ());
+        prune(best, block, comparator);
+    }
+    best.reverse();
+}
+
+// 降順にソート済みの best と昇順にソート済みの block（block.len() <= best.len()）から
+// 小さい方の best.len() 個を選び、降順にソートして best に置く
+// 大きい方の要素は block に移り、block の順序は不定になる
+fn prune<T, F>(best: &mut [T], block: &mut [T], comparator: &F)
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    // best と block を並べた列はバイトニック列（block が短い分は +∞ のパディングとみなす）
+    // 対応する要素どうしを比較して小さい方を best に集めると、best は小さい方の半分になり、
+    // 「上がってから下がる」バイトニック列になる。末尾に -∞ のパディングを足しても
+    // バイトニック列のままなので、降順の sub_sort でソートできる
+    for (a, b) in best.iter_mut().zip(block.iter_mut()) {
+        if comparator(a, b) == Ordering::Greater {
+            mem::swap(a, b);
+        }
+    }
+    sub_sort(best, false, comparator, ());
+}
+
+// do_sort、sub_sort、compare_and_swap と同じ再帰で、キーと値を一緒に並べ替える
+fn do_sort_pairs<K: Ord, V>(keys: &mut [K], values: &mut [V], forward: bool) {
+    if keys.len() > 1 {
+        let mid_point = keys.len() / 2;
+        let (first_keys, second_keys) = keys.split_at_mut(mid_point);
+        let (first_values, second_values) = values.split_at_mut(mid_point);
+        do_sort_pairs(first_keys, first_values, !forward);
+        do_sort_pairs(second_keys, second_values, forward);
+        sub_sort_pairs(keys, values, forward);
+    }
+}
+
+fn sub_sort_pairs<K: Ord, V>(keys: &mut [K], values: &mut [V], forward: bool) {
+    if keys.len() > 1 {
+        compare_and_swap_pairs(keys, values, forward);
+        let mid_point = keys.len().next_power_of_two() / 2;
+        let (first_keys, second_keys) = keys.split_at_mut(mid_point);
+        let (first_values, second_values) = values.split_at_mut(mid_point);
+        sub_sort_pairs(first_keys, first_values, forward);
+        sub_sort_pairs(second_keys, second_values, forward);
+    }
+}
+
+fn compare_and_swap_pairs<K: Ord, V>(keys: &mut [K], values: &mut [V], forward: bool) {
+    let swap_condition = if forward {
+        Ordering::Greater
+    } else {
+        Ordering::Less
+    };
+
+    let mid_point = keys.len().next_power_of_two() / 2;
+
+    for i in 0..(keys.len() - mid_point) {
+        if keys[i].cmp(&keys[mid_point + i]) == swap_condition {
+            // キーと値で同じ交換をする
+            keys.swap(i, mid_point + i);
+            values.swap(i, mid_point + i);
+        }
+    }
 }
 
 // このモジュールは cargo test を実行したときのみコンパイルされる
 #[cfg(test)]
 mod tests {
-    use super::{sort, sort_by};
-    use crate::utils::{is_sorted_ascending, is_sorted_descending, new_u32_vec};
+    use super::{
+        argsort, argsort_by, bitonic_merge, bitonic_merge_by, merge_sorted, partial_sort,
+        partial_sort_by, sort, sort_by, sort_by_cached_key, sort_by_catching, sort_by_key,
+        sort_by_stable, sort_floats, sort_pairs, top_k, try_sort_by,
+    };
+    use crate::float::{FloatOrder, NanPlacement, ZeroOrder};
+    use crate::utils::{apply_permutation, is_sorted_ascending, is_sorted_descending, new_u32_vec};
+    use crate::SortError;
     use crate::SortOrder::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 
     // impl PartialEq for Student {
     //     fn eq(&self, other: &Self) -> bool{
@@ -177,6 +543,152 @@ mod tests {
         assert_eq!(x, expected);
     }
 
+    #[test]
+    fn sort_students_stable_by_age_then_name() {
+        // 同じ年齢の生徒がいるテストデータ
+        let taro = Student::new("Taro", "Yamada", 16);
+        let hanako = Student::new("Hanako", "Yamada", 14);
+        let kyoko = Student::new("Kyoko", "Ito", 15);
+        let ryosuke = Student::new("Ryosuke", "Hayashi", 17);
+        let jiro = Student::new("Jiro", "Suzuki", 16);
+        let akiko = Student::new("Akiko", "Sato", 14);
+
+        let mut x = vec![&taro, &hanako, &kyoko, &ryosuke, &jiro, &akiko];
+
+        // まず副キーの名前でソートし、次に主キーの年齢で安定ソートする
+        // 同じ年齢の生徒は名前の順に並んだままになる
+        assert_eq!(
+            sort_by_stable(&mut x, &|a, b| a.first_name.cmp(&b.first_name)),
+            Ok(())
+        );
+        assert_eq!(sort_by_stable(&mut x, &|a, b| a.age.cmp(&b.age)), Ok(()));
+
+        let expected = vec![&akiko, &hanako, &kyoko, &jiro, &taro, &ryosuke];
+        assert_eq!(x, expected);
+    }
+
+    #[test]
+    fn sort_stable_keeps_original_order() {
+        // (キー, 元の位置) の組を作り、キーだけで安定ソートする
+        let mut x: Vec<(u32, usize)> = new_u32_vec(5000)
+            .into_iter()
+            .map(|n| n % 10)
+            .enumerate()
+            .map(|(i, n)| (n, i))
+            .collect();
+        assert_eq!(sort_by_stable(&mut x, &|a, b| a.0.cmp(&b.0)), Ok(()));
+        // キーが同じなら元の位置の昇順になっている
+        assert!(is_sorted_ascending(&x));
+    }
+
+    #[test]
+    fn sort_students_by_key() {
+        let taro = Student::new("Taro", "Yamada", 16);
+        let hanako = Student::new("Hanako", "Yamada", 14);
+        let kyoko = Student::new("Kyoko", "Ito", 15);
+        let ryosuke = Student::new("Ryosuke", "Hayashi", 17);
+
+        let mut x = vec![&taro, &hanako, &kyoko, &ryosuke];
+        assert_eq!(sort_by_key(&mut x, &|s| s.age), Ok(()));
+        assert_eq!(x, vec![&hanako, &kyoko, &taro, &ryosuke]);
+    }
+
+    #[test]
+    fn sort_str_by_cached_key() {
+        // キーを計算した回数を数える
+        let calls = AtomicUsize::new(0);
+        let mut x = vec![
+            "rust",
+            "Is",
+            "fast",
+            "AND",
+            "Memory-efficient",
+            "with",
+            "no",
+            "gc",
+        ];
+        assert_eq!(
+            sort_by_cached_key(&mut x, &|s| {
+                calls.fetch_add(1, AtomicOrdering::Relaxed);
+                s.to_lowercase()
+            }),
+            Ok(())
+        );
+        assert_eq!(
+            x,
+            vec![
+                "AND",
+                "fast",
+                "gc",
+                "Is",
+                "Memory-efficient",
+                "no",
+                "rust",
+                "with"
+            ]
+        );
+        // キーは要素ごとに一度だけ計算される
+        assert_eq!(calls.load(AtomicOrdering::Relaxed), x.len());
+    }
+
+    #[test]
+    fn argsort_columns() {
+        // 列指向のデータ。ages をキーにして、ほかの列も同じ順序に並べ替える
+        let mut ages: Vec<u8> = vec![16, 14, 15, 17, 14];
+        let mut names = vec!["Taro", "Hanako", "Kyoko", "Ryosuke", "Akiko"];
+        let mut ids: Vec<u32> = vec![1, 2, 3, 4, 5];
+
+        let perm = argsort(&ages, &Ascending).unwrap();
+        // 同じ年齢（14歳）の Hanako と Akiko は元の順序のまま
+        assert_eq!(perm, vec![1, 4, 2, 0, 3]);
+
+        apply_permutation(&mut ages, &perm);
+        apply_permutation(&mut names, &perm);
+        apply_permutation(&mut ids, &perm);
+        assert_eq!(ages, vec![14, 14, 15, 16, 17]);
+        assert_eq!(names, vec!["Hanako", "Akiko", "Kyoko", "Taro", "Ryosuke"]);
+        assert_eq!(ids, vec![2, 5, 3, 1, 4]);
+    }
+
+    #[test]
+    fn argsort_by_does_not_move_data() {
+        let x = new_u32_vec(10_000);
+        let perm = argsort_by(&x, &|a, b| b.cmp(a)).unwrap();
+        let sorted: Vec<u32> = perm.iter().map(|&i| x[i]).collect();
+        assert!(is_sorted_descending(&sorted));
+    }
+
+    #[test]
+    fn sort_pairs_moves_values_with_keys() {
+        let mut keys: Vec<u32> = vec![16, 14, 15, 17, 13];
+        let mut values = vec!["Taro", "Hanako", "Kyoko", "Ryosuke", "Akiko"];
+        assert_eq!(sort_pairs(&mut keys, &mut values, &Descending), Ok(()));
+        assert_eq!(keys, vec![17, 16, 15, 14, 13]);
+        assert_eq!(values, vec!["Ryosuke", "Taro", "Kyoko", "Hanako", "Akiko"]);
+    }
+
+    #[test]
+    fn sort_pairs_large() {
+        let mut keys = new_u32_vec(20_000);
+        // 値にはキーから計算できるものを入れておき、一緒に移動したことを確認する
+        let mut values: Vec<u64> = keys.iter().map(|&k| k as u64 * 2).collect();
+        assert_eq!(sort_pairs(&mut keys, &mut values, &Ascending), Ok(()));
+        assert!(is_sorted_ascending(&keys));
+        assert!(keys.iter().zip(&values).all(|(&k, &v)| v == k as u64 * 2));
+    }
+
+    #[test]
+    fn sort_pairs_length_mismatch() {
+        let mut keys: Vec<u32> = vec![3, 1, 2];
+        let mut values = vec!["c", "a"];
+        assert_eq!(
+            sort_pairs(&mut keys, &mut values, &Ascending),
+            Err(SortError::LengthMismatch { keys: 3, values: 2 })
+        );
+        // エラーのときは何も並べ替えない
+        assert_eq!(keys, vec![3, 1, 2]);
+    }
+
     // #[test] のついた関数はcargo testしたときに実行される
     #[test]
     fn sort_32_ascending() {
@@ -257,9 +769,256 @@ mod tests {
     }
 
     #[test]
-    fn sort_to_fail() {
+    fn merge_sorted_unequal_lengths() {
+        let a = vec![3, 10, 11, 42];
+        let b = vec![1, 4, 20];
+        let mut out = vec![0; 7];
+        assert_eq!(merge_sorted(&a, &b, &mut out, &Ascending), Ok(()));
+        assert_eq!(out, vec![1, 3, 4, 10, 11, 20, 42]);
+
+        let a = vec!["rust", "is", "and"];
+        let b = vec!["with", "fast"];
+        let mut out = vec![""; 5];
+        assert_eq!(merge_sorted(&a, &b, &mut out, &Descending), Ok(()));
+        assert_eq!(out, vec!["with", "rust", "is", "fast", "and"]);
+    }
+
+    #[test]
+    fn merge_sorted_length_mismatch() {
+        let mut out = vec![0; 4];
+        assert_eq!(
+            merge_sorted(&[1, 2], &[3, 4, 5], &mut out, &Ascending),
+            Err(SortError::OutputLengthMismatch {
+                expected: 5,
+                actual: 4
+            })
+        );
+    }
+
+    #[test]
+    fn bitonic_merge_mid_out_of_range() {
+        let mut x = vec![1, 3, 2];
+        assert_eq!(
+            bitonic_merge(&mut x, 4, &Ascending),
+            Err(SortError::MidOutOfRange { mid: 4, len: 3 })
+        );
+        assert_eq!(x, vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn bitonic_merge_every_split() {
+        // 前半と後半の長さのすべての組み合わせについて、マージの結果がソート済みになる
+        for len in 0..=100 {
+            for mid in 0..=len {
+                let mut x = new_u32_vec(len);
+                x[..mid].sort();
+                x[mid..].sort();
+                let mut expected = x.clone();
+                expected.sort();
+                assert_eq!(bitonic_merge(&mut x, mid, &Ascending), Ok(()));
+                assert_eq!(x, expected, "len: {}, mid: {}", len, mid);
+            }
+        }
+    }
+
+    #[test]
+    fn bitonic_merge_incrementally() {
+        // ソート済みのバッファに、ソート済みの列を追加していく
+        let mut buffer: Vec<u32> = Vec::new();
+        for len in &[10, 1, 33, 100, 7] {
+            let mut run = new_u32_vec(*len);
+            sort(&mut run, &Descending).unwrap();
+            let mid = buffer.len();
+            buffer.extend(run);
+            assert_eq!(bitonic_merge_by(&mut buffer, mid, &|a, b| b.cmp(a)), Ok(()));
+            assert!(is_sorted_descending(&buffer));
+        }
+        assert_eq!(buffer.len(), 151);
+    }
+
+    #[test]
+    fn top_k_students() {
+        let taro = Student::new("Taro", "Yamada", 16);
+        let hanako = Student::new("Hanako", "Yamada", 14);
+        let kyoko = Student::new("Kyoko", "Ito", 15);
+        let ryosuke = Student::new("Ryosuke", "Hayashi", 17);
+        let jiro = Student::new("Jiro", "Suzuki", 18);
+
+        // 年上の2人を選ぶ
+        let mut x = vec![&taro, &hanako, &kyoko, &ryosuke, &jiro];
+        assert_eq!(
+            partial_sort_by(&mut x, 2, &|a, b| b.age.cmp(&a.age)),
+            Ok(())
+        );
+        assert_eq!(x[..2], [&jiro, &ryosuke]);
+
+        let x = vec![10, 30, 11, 20, 4, 330, 21, 110];
+        assert_eq!(top_k(&x, 3, &Ascending), Ok(vec![4, 10, 11]));
+        assert_eq!(top_k(&x, 3, &Descending), Ok(vec![330, 110, 30]));
+        assert_eq!(top_k(&x, 0, &Ascending), Ok(vec![]));
+        // k が要素数より大きければ、すべての要素をソートして返す
+        assert_eq!(top_k(&x[..3], 5, &Ascending), Ok(vec![10, 11, 30]));
+    }
+
+    #[test]
+    fn partial_sort_every_k() {
+        for &len in &[0, 1, 2, 7, 64, 100, 257] {
+            for k in 0..=len + 1 {
+                let mut expected = new_u32_vec(len);
+                expected.sort();
+                let k = k.min(len);
+
+                let mut x = new_u32_vec(len);
+                assert_eq!(partial_sort(&mut x, k, &Ascending), Ok(()));
+                assert_eq!(x[..k], expected[..k], "len: {}, k: {}", len, k);
+                // 残りの要素も失われていない
+                x.sort();
+                assert_eq!(x, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn sort_floats_total_order() {
+        let mut x = vec![
+            1.5,
+            f64::NAN,
+            -0.0,
+            f64::NEG_INFINITY,
+            0.0,
+            -f64::NAN,
+            -2.0,
+            0.0,
+        ];
+        assert_eq!(
+            sort_floats(&mut x, &Ascending, &FloatOrder::default()),
+            Ok(())
+        );
+        // -NaN < -∞ < -2.0 < -0.0 < +0.0 < 1.5 < NaN
+        assert!(x[0].is_nan() && x[0].is_sign_negative());
+        assert_eq!(x[1..7], [f64::NEG_INFINITY, -2.0, -0.0, 0.0, 0.0, 1.5]);
+        assert!(x[3].is_sign_negative() && x[4].is_sign_positive());
+        assert!(x[7].is_nan() && x[7].is_sign_positive());
+    }
+
+    #[test]
+    fn sort_floats_nan_first_and_last() {
+        let x = vec![1.5f32, f32::NAN, -0.0, 3.0, -f32::NAN, -2.0, 0.0];
+        for &(nan, order) in &[
+            (NanPlacement::First, Ascending),
+            (NanPlacement::First, Descending),
+            (NanPlacement::Last, Ascending),
+            (NanPlacement::Last, Descending),
+        ] {
+            let mut x = x.clone();
+            let float_order = FloatOrder {
+                nan,
+                zero: ZeroOrder::Equal,
+            };
+            assert_eq!(sort_floats(&mut x, &order, &float_order), Ok(()));
+
+            let (nans, numbers) = match nan {
+                NanPlacement::First => x.split_at(2),
+                _ => {
+                    let (numbers, nans) = x.split_at(5);
+                    (nans, numbers)
+                }
+            };
+            assert!(nans.iter().all(|v| v.is_nan()));
+            // -0.0 と +0.0 は等しいとみなすので、== で比べる
+            let expected = match order {
+                Ascending => [-2.0, 0.0, 0.0, 1.5, 3.0],
+                Descending => [3.0, 1.5, 0.0, 0.0, -2.0],
+            };
+            assert_eq!(numbers, expected);
+        }
+    }
+
+    #[test]
+    fn try_sort_by_stops_at_first_error() {
+        // 学生の名簿を ID で引いてから、年齢で比較する
+        let taro = Student::new("Taro", "Yamada", 16);
+        let hanako = Student::new("Hanako", "Yamada", 14);
+        let kyoko = Student::new("Kyoko", "Ito", 15);
+        let roster = [&taro, &hanako, &kyoko];
+        let lookup = |id: &usize| roster.get(*id).ok_or(format!("unknown id: {}", id));
+
+        let mut x = vec![0, 2, 1];
+        let result: Result<(), String> =
+            try_sort_by(&mut x, &|a, b| Ok(lookup(a)?.age.cmp(&lookup(b)?.age)));
+        assert_eq!(result, Ok(()));
+        assert_eq!(x, vec![1, 2, 0]);
+
+        // エラーを返したあとは comparator が呼ばれない
+        let failed = AtomicUsize::new(0);
+        let mut x: Vec<usize> = vec![0, 2, 1, 3, 1, 0, 2, 1];
+        let result = try_sort_by(&mut x, &|a, b| {
+            assert_eq!(failed.load(AtomicOrdering::Relaxed), 0);
+            let ordering = lookup(a).and_then(|a| Ok(a.age.cmp(&lookup(b)?.age)));
+            if ordering.is_err() {
+                failed.fetch_add(1, AtomicOrdering::Relaxed);
+            }
+            ordering
+        });
+        assert_eq!(result, Err("unknown id: 3".to_string()));
+        assert_eq!(failed.load(AtomicOrdering::Relaxed), 1);
+        // x は入力の並べ替えのまま
+        x.sort();
+        assert_eq!(x, vec![0, 0, 1, 1, 1, 2, 2, 3]);
+    }
+
+    #[test]
+    fn sort_non_power_of_two() {
         let mut x = vec![10, 30, 11];
-        assert!(sort(&mut x, &Ascending).is_err())
+        assert_eq!(sort(&mut x, &Ascending), Ok(()));
+        assert_eq!(x, vec![10, 11, 30]);
+    }
+
+    #[test]
+    fn sort_every_length() {
+        // 0から 300要素まで、すべての長さについて昇順と降順を検証する
+        for len in 0..=300 {
+            let mut x = new_u32_vec(len);
+            assert_eq!(sort(&mut x, &Ascending), Ok(()));
+            assert!(is_sorted_ascending(&x));
+
+            let mut x = new_u32_vec(len);
+            assert_eq!(sort(&mut x, &Descending), Ok(()));
+            assert!(is_sorted_descending(&x));
+        }
+    }
+
+    #[test]
+    fn sort_comparator_panicked() {
+        let comparator = |a: &u32, b: &u32| {
+            if *a == 11 || *b == 11 {
+                panic!("11 is not comparable");
+            }
+            a.cmp(b)
+        };
+        // sort_by はパニックをそのまま伝える
+        let mut x = vec![10, 30, 11, 20];
+        let payload =
+            panic::catch_unwind(AssertUnwindSafe(|| sort_by(&mut x, &comparator))).unwrap_err();
+        assert_eq!(
+            payload.downcast_ref::<&str>(),
+            Some(&"11 is not comparable")
+        );
+        x.sort();
+        assert_eq!(x, vec![10, 11, 20, 30]);
+
+        // sort_by_catching は Err にする
+        let mut x = vec![10, 30, 11, 20];
+        let result = sort_by_catching(&mut x, &comparator);
+        assert_eq!(
+            result,
+            Err(SortError::ComparatorPanicked(
+                "11 is not comparable".to_string()
+            ))
+        );
+        // パニックしても要素は失われない
+        x.sort();
+        assert_eq!(x, vec![10, 11, 20, 30]);
     }
 
     // #[test]
@@ -284,5 +1043,11 @@ mod tests {
             assert_eq!(sort(&mut x, &Descending), Ok(()));
             assert!(is_sorted_descending(&x));
         };
+        {
+            // 2のべき乗でない要素数
+            let mut x = new_u32_vec(50_000);
+            assert_eq!(sort(&mut x, &Ascending), Ok(()));
+            assert!(is_sorted_ascending(&x));
+        };
     }
 }
@@ -0,0 +1,589 @@
+// 教材やデバッグのために、ソート中の compare_and_swap をすべて記録する
+// second と third の compare_and_swap は比較のたびに Observer に通知する。通常のソートでは
+// 何もしない () を渡すので、記録のコストはかからない。トレースを取るソートでは Tracer を渡す
+// 記録したトレースは、ANSI エスケープシーケンスによる端末のアニメーションか、
+// アニメーションする SVG として再生できる
+use super::fourth::ParallelSortConfig;
+use super::{third, SortError, SortOrder};
+use std::cmp::Ordering;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+use std::time::Duration;
+
+// 1回の compare_and_swap の記録
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent<T> {
+    // 比較した2要素の、スライス全体でのインデックス（i < j）
+    pub i: usize,
+    pub j: usize,
+    // 比較したときの x[i] と x[j] の値（交換する前の値）
+    pub a: T,
+    pub b: T,
+    pub swapped: bool,
+    // 比較を実行したスレッド
+    pub thread: ThreadId,
+}
+
+// ソート前の値と、実行した順の compare_and_swap の記録
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace<T> {
+    pub initial: Vec<T>,
+    pub events: Vec<TraceEvent<T>>,
+}
+
+// compare_and_swap が比較するたびに呼ばれる
+// () は何もしないので、トレースしないソートでは最適化で消える
+// トレースのほか、third の統計（&LocalStats、stats.rs で実装）にも使う
+pub(crate) trait Observer<T>: Copy {
+    // x[offset..] に再帰するときに、インデックスを offset だけずらした Observer を返す
+    fn shift(self, offset: usize) -> Self;
+    // compare_and_swap を始めた（マージのステップを1つ実行する）
+    fn stage(self) {}
+    // x[i] と x[j] を比較した。a と b は交換する前の値
+    fn compared(self, i: usize, j: usize, a: &T, b: &T, swapped: bool);
+}
+
+impl<T> Observer<T> for () {
+    fn shift(self, _offset: usize) -> Self {}
+
+    fn compared(self, _i: usize, _j: usize, _a: &T, _b: &T, _swapped: bool) {}
+}
+
+// 比較をトレースに記録する Observer
+// 並列版では複数のスレッドから記録するので、イベントの列は Mutex で守る
+// ロックを取った順に並ぶので、同じ要素に触れるイベントどうしは実行した順になる
+pub(crate) struct Tracer<'a, T> {
+    events: &'a Mutex<Vec<TraceEvent<T>>>,
+    offset: usize,
+}
+
+// derive すると T: Copy が必要になるので、手で実装する
+impl<T> Clone for Tracer<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Tracer<'_, T> {}
+
+impl<T: Clone> Observer<T> for Tracer<'_, T> {
+    fn shift(self, offset: usize) -> Self {
+        Self {
+            offset: self.offset + offset,
+            ..self
+        }
+    }
+
+    fn compared(self, i: usize, j: usize, a: &T, b: &T, swapped: bool) {
+        let event = TraceEvent {
+            i: self.offset + i,
+            j: self.offset + j,
+            a: a.clone(),
+            b: b.clone(),
+            swapped,
+            thread: thread::current().id(),
+        };
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl<T: Clone> Trace<T> {
+    // x の今の値を初期状態として、sort が Tracer に記録した比較をトレースにする
+    pub(crate) fn record<S>(x: &mut [T], sort: S) -> Result<Self, SortError>
+    where
+        S: FnOnce(&mut [T], Tracer<T>) -> Result<(), SortError>,
+    {
+        let initial = x.to_vec();
+        let events = Mutex::new(Vec::new());
+        sort(
+            x,
+            Tracer {
+                events: &events,
+                offset: 0,
+            },
+        )?;
+        Ok(Self {
+            initial,
+            events: events.into_inner().unwrap(),
+        })
+    }
+
+    // トレースを最後まで再生したあとの値。ソートした結果と一致する
+    pub fn replay(&self) -> Vec<T> {
+        let mut x = self.initial.clone();
+        for event in &self.events {
+            if event.swapped {
+                x.swap(event.i, event.j);
+            }
+        }
+        x
+    }
+}
+
+impl<T> Trace<T> {
+    // 比較を実行したスレッドの数
+    pub fn thread_count(&self) -> usize {
+        self.thread_numbers().into_iter().max().map_or(0, |n| n + 1)
+    }
+
+    // イベントごとに、スレッドが最初に現れた順の番号（0から）を返す
+    fn thread_numbers(&self) -> Vec<usize> {
+        let mut threads: Vec<ThreadId> = Vec::new();
+        self.events
+            .iter()
+            .map(
+                |event| match threads.iter().position(|&t| t == event.thread) {
+                    Some(n) => n,
+                    None => {
+                        threads.push(event.thread);
+                        threads.len() - 1
+                    }
+                },
+            )
+            .collect()
+    }
+}
+
+// 再生するときは、値の代わりに値の順位（0から）を棒の長さにする
+// こうすると T が数値でなくても描けて、値の大きさの偏りにも影響されない
+// 再生できる要素数は MAX_REPLAY_LEN まで。端末では1要素が1行で、棒の長さは要素数に比例するので、
+// 実際に見られるのは数十要素まで。フレームの数は要素数を n として n * log(n)^2 程度に増える
+// 記録は要素数によらずできるので、上限を超えたトレースを再生すると SortError::TooManyToReplay を返す
+pub const MAX_REPLAY_LEN: usize = 256;
+
+impl<T: Ord> Trace<T> {
+    // 初期状態で、各位置にある要素の順位を返す
+    fn initial_ranks(&self) -> Result<Vec<usize>, SortError> {
+        if self.initial.len() > MAX_REPLAY_LEN {
+            return Err(SortError::TooManyToReplay {
+                len: self.initial.len(),
+                max: MAX_REPLAY_LEN,
+            });
+        }
+        let mut indices: Vec<usize> = (0..self.initial.len()).collect();
+        // 等しい値は元の位置の順に順位をつけ、どの棒も異なる長さにする
+        indices.sort_by(|&i, &j| self.initial[i].cmp(&self.initial[j]).then(i.cmp(&j)));
+        let mut ranks = vec![0; indices.len()];
+        for (rank, &i) in indices.iter().enumerate() {
+            ranks[i] = rank;
+        }
+        Ok(ranks)
+    }
+
+    // 端末に表示するフレームを1つずつ作る。要素数は events.len() + 1
+    // 順位の配列は1つだけ持ち、イベントごとに交換しながら描く
+    fn ansi_frames(&self) -> Result<impl Iterator<Item = String> + '_, SortError> {
+        const RED: &str = "\x1b[31m";
+        const GREEN: &str = "\x1b[32m";
+        const RESET: &str = "\x1b[0m";
+
+        let threads = self.thread_numbers();
+        let total = self.events.len();
+        let mut ranks = self.initial_ranks()?;
+        Ok((0..=total).map(move |step| {
+            let mut frame = String::new();
+            // 最初のフレームは初期状態で、比較した要素はない
+            let event = step.checked_sub(1).map(|n| (&self.events[n], threads[n]));
+            match event {
+                Some((event, thread)) => {
+                    if event.swapped {
+                        ranks.swap(event.i, event.j);
+                    }
+                    writeln!(
+                        frame,
+                        "step {}/{}  thread {}  x[{}] <-> x[{}]  {}",
+                        step,
+                        total,
+                        thread,
+                        event.i,
+                        event.j,
+                        if event.swapped { "swapped" } else { "kept" }
+                    )
+                }
+                None => writeln!(frame, "step 0/{}", total),
+            }
+            .unwrap();
+            for (i, &rank) in ranks.iter().enumerate() {
+                let color = match event {
+                    Some((e, _)) if i == e.i || i == e.j => {
+                        if e.swapped {
+                            RED
+                        } else {
+                            GREEN
+                        }
+                    }
+                    _ => "",
+                };
+                let reset = if color.is_empty() { "" } else { RESET };
+                writeln!(frame, "{:>4} {}{}{}", i, color, "#".repeat(rank + 1), reset).unwrap();
+            }
+            frame
+        }))
+    }
+
+    // 端末に表示するフレームを返す。1行が1要素で、比較した2要素の棒に色をつける
+    // 交換したなら赤、交換しなかったなら緑で、交換したあとの状態を描く
+    pub fn to_ansi_frames(&self) -> Result<Vec<String>, SortError> {
+        Ok(self.ansi_frames()?.collect())
+    }
+
+    // 画面を消してからフレームを1つずつ out に書き、delay だけ待つことを繰り返す
+    // 要素数が多すぎて再生できないときは、何も書かずに InvalidInput の io::Error を返す
+    // （中身は SortError::TooManyToReplay）
+    pub fn play_ansi<W: Write>(&self, out: &mut W, delay: Duration) -> io::Result<()> {
+        let frames = self
+            .ansi_frames()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        for frame in frames {
+            // カーソルを左上に移動して画面を消す
+            write!(out, "\x1b[H\x1b[2J{}", frame)?;
+            out.flush()?;
+            thread::sleep(delay);
+        }
+        Ok(())
+    }
+
+    // アニメーションする SVG を返す。1フレームの長さは frame_ms ミリ秒
+    // 要素は縦の棒で、比較するたびに棒が入れ替わる。比較した2要素の下には、
+    // 比較を実行したスレッドごとに色を変えた印を表示する
+    // SMIL の <animate> を使うので、ブラウザで開くと再生される
+    pub fn to_svg(&self, frame_ms: usize) -> Result<String, SortError> {
+        const MARGIN: usize = 20;
+        const BAR_WIDTH: usize = 12;
+        const BAR_GAP: usize = 2;
+        const UNIT_HEIGHT: usize = 6;
+        const MARKER_HEIGHT: usize = 6;
+        const COLORS: [&str; 8] = [
+            "#e41a1c", "#377eb8", "#4daf4a", "#984ea3", "#ff7f00", "#a65628", "#f781bf", "#999999",
+        ];
+
+        let len = self.initial.len();
+        let frame_count = self.events.len() + 1;
+        let threads = self.thread_numbers();
+        let bar_x = |i: usize| MARGIN + (BAR_WIDTH + BAR_GAP) * i;
+        let width = MARGIN * 2 + (BAR_WIDTH + BAR_GAP) * len;
+        let base_y = MARGIN + UNIT_HEIGHT * len;
+        let height = base_y + MARKER_HEIGHT * 2 + MARGIN;
+
+        // 順位ごとに、棒の位置が変わったフレームとその位置を記録する
+        // 位置の逆引き（順位 -> 位置）は交換のたびに更新するので、フレームごとに探さなくてよい
+        let mut ranks = self.initial_ranks()?;
+        let mut moves: Vec<Vec<(usize, usize)>> = vec![Vec::new(); len];
+        for (i, &rank) in ranks.iter().enumerate() {
+            moves[rank].push((0, i));
+        }
+        for (n, event) in self.events.iter().enumerate() {
+            if event.swapped {
+                ranks.swap(event.i, event.j);
+                moves[ranks[event.i]].push((n + 1, event.i));
+                moves[ranks[event.j]].push((n + 1, event.j));
+            }
+        }
+
+        // （フレーム番号, 値）の並びから <animate> を作る。keyTimes はフレームの開始時刻（0〜1）
+        // calcMode="discrete" なので、次の keyTimes までは同じ値を表示し続ける
+        // 最後のフレームは1フレーム分表示してから止める
+        let animate = |attribute: &str, frames: &mut dyn Iterator<Item = (usize, String)>| {
+            let (key_times, values): (Vec<String>, Vec<String>) = frames
+                .map(|(n, value)| (format!("{:.6}", n as f64 / frame_count as f64), value))
+                .unzip();
+            format!(
+                r#"<animate attributeName="{}" values="{}" keyTimes="{}" dur="{}ms" calcMode="discrete" fill="freeze"/>"#,
+                attribute,
+                values.join(";"),
+                key_times.join(";"),
+                frame_ms * frame_count
+            )
+        };
+
+        let mut svg = String::new();
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#,
+            width, height, width, height
+        )
+        .unwrap();
+        writeln!(svg, r#"  <rect width="100%" height="100%" fill="white"/>"#).unwrap();
+
+        // 順位 rank の棒が、位置が変わるフレームでどこへ移るかをアニメーションさせる
+        for (rank, moves) in moves.iter().enumerate() {
+            let bar_height = UNIT_HEIGHT * (rank + 1);
+            writeln!(
+                svg,
+                r#"  <rect x="{}" y="{}" width="{}" height="{}" fill="gray">{}</rect>"#,
+                bar_x(moves[0].1),
+                base_y - bar_height,
+                BAR_WIDTH,
+                bar_height,
+                animate(
+                    "x",
+                    &mut moves.iter().map(|&(n, i)| (n, bar_x(i).to_string()))
+                )
+            )
+            .unwrap();
+        }
+
+        // 比較した2要素の下の印。最初のフレームでは表示しない
+        if !self.events.is_empty() {
+            for &second in &[false, true] {
+                let xs = std::iter::once(0)
+                    .chain(self.events.iter().map(|e| if second { e.j } else { e.i }))
+                    .map(|i| bar_x(i).to_string());
+                let colors = std::iter::once("none")
+                    .chain(threads.iter().map(|&t| COLORS[t % COLORS.len()]))
+                    .map(|color| color.to_string());
+                writeln!(
+                    svg,
+                    r#"  <rect x="{}" y="{}" width="{}" height="{}" fill="none">{}{}</rect>"#,
+                    bar_x(0),
+                    base_y + MARKER_HEIGHT,
+                    BAR_WIDTH,
+                    MARKER_HEIGHT,
+                    animate("x", &mut xs.enumerate()),
+                    animate("fill", &mut colors.enumerate())
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(svg, "</svg>").unwrap();
+        Ok(svg)
+    }
+}
+
+// 比較をトレースしながら、config の設定で並列にソートする
+// 再帰は fourth::do_sort と同じだが、compare_and_swap と閾値未満の部分は third の関数を使う
+// config.hybrid と config.stats は使わない
+pub fn par_sort_by_traced<T, F>(
+    x: &mut [T],
+    comparator: &F,
+    config: &ParallelSortConfig,
+) -> Result<Trace<T>, SortError>
+where
+    T: Clone + Send + Sync,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    Trace::record(x, |x, tracer| {
+        config.install(|| do_sort(x, true, comparator, config, 0, tracer));
+        Ok(())
+    })
+}
+
+pub fn par_sort_traced<T: Ord + Clone + Send + Sync>(
+    x: &mut [T],
+    order: &SortOrder,
+    config: &ParallelSortConfig,
+) -> Result<Trace<T>, SortError> {
+    match *order {
+        SortOrder::Ascending => par_sort_by_traced(x, &|a, b| a.cmp(b), config),
+        SortOrder::Descending => par_sort_by_traced(x, &|a, b| b.cmp(a), config),
+    }
+}
+
+fn do_sort<T, F>(
+    x: &mut [T],
+    forward: bool,
+    comparator: &F,
+    config: &ParallelSortConfig,
+    depth: usize,
+    tracer: Tracer<T>,
+) where
+    T: Clone + Send + Sync,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    if x.len() > 1 {
+        let mid_point = x.len() / 2;
+        let (first, second) = x.split_at_mut(mid_point);
+        if config.parallel_sort(mid_point, depth) {
+            rayon::join(
+                || do_sort(first, !forward, comparator, config, depth + 1, tracer),
+                || {
+                    let tracer = tracer.shift(mid_point);
+                    do_sort(second, forward, comparator, config, depth + 1, tracer)
+                },
+            );
+        } else {
+            third::do_sort(first, !forward, comparator, tracer);
+            third::do_sort(second, forward, comparator, tracer.shift(mid_point));
+        }
+        sub_sort(x, forward, comparator, config, depth, tracer);
+    }
+}
+
+fn sub_sort<T, F>(
+    x: &mut [T],
+    forward: bool,
+    comparator: &F,
+    config: &ParallelSortConfig,
+    depth: usize,
+    tracer: Tracer<T>,
+) where
+    T: Clone + Send + Sync,
+    F: Sync + Fn(&T, &T) -> Ordering,
+{
+    if x.len() > 1 {
+        third::compare_and_swap(x, forward, comparator, tracer);
+        let mid_point = x.len().next_power_of_two() / 2;
+        let (first, second) = x.split_at_mut(mid_point);
+        if config.parallel_sub_sort(mid_point, depth) {
+            rayon::join(
+                || sub_sort(first, forward, comparator, config, depth + 1, tracer),
+                || {
+                    let tracer = tracer.shift(mid_point);
+                    sub_sort(second, forward, comparator, config, depth + 1, tracer)
+                },
+            );
+        } else {
+            third::sub_sort(first, forward, comparator, tracer);
+            third::sub_sort(second, forward, comparator, tracer.shift(mid_point));
+        }
+    }
+}
+
+// このモジュールは cargo test を実行したときのみコンパイルされる
+#[cfg(test)]
+mod tests {
+    use super::{par_sort_traced, MAX_REPLAY_LEN};
+    use crate::fourth::ParallelSortConfig;
+    use crate::network::Network;
+    use crate::utils::{is_sorted_ascending, new_u32_vec};
+    use crate::SortOrder::*;
+    use crate::{second, third, SortError};
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    fn trace_matches_network() {
+        // 記録した比較の順序は、third のネットワークの比較器と一致する
+        for len in 0..=40 {
+            let mut x = new_u32_vec(len);
+            let trace = third::sort_traced(&mut x, &Ascending).unwrap();
+            assert!(is_sorted_ascending(&x));
+            assert_eq!(trace.replay(), x);
+            assert_eq!(trace.thread_count(), len.min(2) / 2);
+
+            let mut comparators: Vec<(usize, usize)> = Network::bitonic(len)
+                .comparators()
+                .map(|c| (c.i, c.j))
+                .collect();
+            let mut events: Vec<(usize, usize)> = trace.events.iter().map(|e| (e.i, e.j)).collect();
+            // Network はステージ順に並べ替えているので、集合として比べる
+            comparators.sort();
+            events.sort();
+            assert_eq!(events, comparators);
+        }
+    }
+
+    #[test]
+    fn trace_records_values() {
+        let mut x = vec![3, 1, 2, 0];
+        let trace = second::sort_traced(&mut x, &Descending).unwrap();
+        assert_eq!(x, vec![3, 2, 1, 0]);
+        assert_eq!(trace.initial, vec![3, 1, 2, 0]);
+        // 最初の比較は x[0] と x[1] を昇順に並べる
+        let first = &trace.events[0];
+        assert_eq!((first.i, first.j, first.a, first.b), (0, 1, 3, 1));
+        assert!(first.swapped);
+        // 各イベントの a と b は、それまでのイベントを再生した値と一致する
+        let mut y = trace.initial.clone();
+        for event in &trace.events {
+            assert_eq!((y[event.i], y[event.j]), (event.a, event.b));
+            if event.swapped {
+                y.swap(event.i, event.j);
+            }
+        }
+        assert_eq!(y, x);
+    }
+
+    #[test]
+    fn par_trace_uses_threads() {
+        let config = ParallelSortConfig {
+            sort_threshold: 64,
+            sub_sort_threshold: 64,
+            ..Default::default()
+        };
+        let mut x = new_u32_vec(1000);
+        let trace = par_sort_traced(&mut x, &Ascending, &config).unwrap();
+        assert!(is_sorted_ascending(&x));
+        // 並列に記録しても、ロックを取った順に再生すれば同じ結果になる
+        assert_eq!(trace.replay(), x);
+        assert_eq!(
+            trace.events.len(),
+            Network::bitonic(1000).comparators().count()
+        );
+        assert!(trace.thread_count() >= 1);
+    }
+
+    #[test]
+    fn replay_ansi_and_svg() {
+        let mut x = vec![5, 2, 7, 2, 0];
+        let trace = third::sort_traced(&mut x, &Ascending).unwrap();
+        let frames = trace.to_ansi_frames().unwrap();
+        assert_eq!(frames.len(), trace.events.len() + 1);
+        // 最後のフレームでは棒の長さが1から順に並ぶ
+        let bars: Vec<usize> = frames
+            .last()
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.matches('#').count())
+            .collect();
+        assert_eq!(bars, vec![1, 2, 3, 4, 5]);
+
+        let svg = trace.to_svg(100).unwrap();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        // 棒が5本と、比較した要素の印が2つ
+        assert_eq!(svg.matches("<rect").count(), 1 + 5 + 2);
+        assert_eq!(
+            svg.matches(&format!(r#"dur="{}ms""#, 100 * frames.len()))
+                .count(),
+            5 + 2 * 2
+        );
+    }
+
+    #[test]
+    fn svg_animates_only_moves() {
+        // 棒の値は、初期位置と、交換で位置が変わったフレームの分だけ
+        // 交換1回で2本の棒が動くので、値の総数は要素数 + 交換の回数 * 2
+        for mut x in [(0..8).rev().collect(), new_u32_vec(50)] {
+            let trace = third::sort_traced(&mut x, &Ascending).unwrap();
+            let svg = trace.to_svg(100).unwrap();
+            let swaps = trace.events.iter().filter(|e| e.swapped).count();
+            let values: usize = svg
+                .lines()
+                .filter(|line| line.contains(r#"fill="gray""#))
+                .map(|line| line.split("values=\"").nth(1).unwrap())
+                .map(|rest| rest.split('"').next().unwrap().split(';').count())
+                .sum();
+            assert_eq!(values, x.len() + swaps * 2);
+        }
+    }
+
+    #[test]
+    fn replay_too_many_elements() {
+        // 記録はできるが、再生はパニックせずにエラーを返す
+        let mut x: Vec<usize> = (0..MAX_REPLAY_LEN + 1).collect();
+        let trace = third::sort_traced(&mut x, &Ascending).unwrap();
+        let err = SortError::TooManyToReplay {
+            len: MAX_REPLAY_LEN + 1,
+            max: MAX_REPLAY_LEN,
+        };
+        assert_eq!(trace.to_svg(100), Err(err.clone()));
+        assert_eq!(trace.to_ansi_frames(), Err(err));
+
+        let mut out = Vec::new();
+        let e = trace
+            .play_ansi(&mut out, Duration::from_millis(0))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(out.is_empty());
+
+        // 上限ちょうどなら再生できる
+        let mut x: Vec<usize> = (0..MAX_REPLAY_LEN).rev().collect();
+        let trace = third::sort_traced(&mut x, &Ascending).unwrap();
+        assert!(trace.to_svg(100).is_ok());
+    }
+}
@@ -28,3 +28,32 @@ pub fn is_sorted_ascending<T: Ord>(x: &[T]) -> bool {
 pub fn is_sorted_descending<T: Ord>(x: &[T]) -> bool {
     x.windows(2).all(|pair| pair[0] >= pair[1])
 }
+
+// perm[i] 番目の要素が i 番目に来るように x を並べ替える（追加のメモリは perm と同じ長さの
+// フラグだけ）。perm は 0..x.len() の並べ替えでなければならない
+// argsort の結果を渡せば、キーと同じ順序で x を並べ替えられる。列指向のデータのように
+// キーと対応する値が別々のスライスになっている場合は、スライスごとに呼び出せばよい
+pub fn apply_permutation<T>(x: &mut [T], perm: &[usize]) {
+    assert_eq!(x.len(), perm.len());
+    let mut done = vec![false; perm.len()];
+
+    // 置換を巡回（サイクル）に分解し、サイクルごとに swap で要素を移動する
+    for start in 0..perm.len() {
+        if done[start] {
+            continue;
+        }
+        let mut current = start;
+        loop {
+            done[current] = true;
+            let next = perm[current];
+            if next == start {
+                break;
+            }
+            // 同じ位置が二度現れたら perm は並べ替えになっていない
+            assert!(!done[next], "perm is not a permutation");
+            // current 番目に、元の next 番目の要素を持ってくる
+            x.swap(current, next);
+            current = next;
+        }
+    }
+}
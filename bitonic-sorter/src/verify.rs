@@ -0,0 +1,250 @@
+// 0-1原理によるソーティングネットワークの検証
+// 0-1原理：比較器のネットワークが 0 と 1 だけからなる 2^n 通りの入力をすべて昇順にソートできるなら、
+// 任意の入力をソートできる。そこで 2^n 通りの入力をすべて試せば、ネットワークの正しさを証明できる
+//
+// 入力はビットスライスで持つ。ワイヤー（要素）ごとに u64 をひとつ用意し、その k ビット目を
+// k 番目の入力の値とすれば、64通りの入力を AND と OR だけでまとめて比較器に通せる
+use super::network::{Comparator, Network};
+use super::SortOrder;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64Mcg;
+use rayon::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+
+// 2^len 通りの 0-1入力をすべて試し、昇順にソートできない入力を返す
+// None ならネットワークはすべての入力をソートできる（len は 64 未満であること）
+pub fn find_counterexample(network: &Network) -> Option<Vec<u8>> {
+    let len = network.len;
+    assert!(len < 64, "exhaustive check needs len < 64 (len: {})", len);
+
+    // 64通りの入力をひとまとめにした「バッチ」の数
+    let total = 1u64 << len;
+    let batches = total.div_ceil(64);
+
+    (0..batches).into_par_iter().find_map_any(|batch| {
+        // バッチの中の k 番目の入力は、数値 batch * 64 + k の各ビットを各ワイヤーの値とする
+        let base = batch * 64;
+        let input: Vec<u64> = (0..len).map(|wire| lanes_of_bit(base, wire)).collect();
+        // 入力の数が 64 に満たない場合（len < 6）は、余ったビットを無視する
+        let valid = if total - base >= 64 {
+            u64::MAX
+        } else {
+            (1u64 << (total - base)) - 1
+        };
+        check_batch(network, &input, valid)
+    })
+}
+
+// ランダムな 0-1入力を samples 回 × 64通り試し、昇順にソートできない入力を返す
+// 要素数が大きく、すべての入力を試せないネットワーク向け。None でも正しさの証明にはならない
+pub fn find_counterexample_random(network: &Network, samples: usize, seed: u64) -> Option<Vec<u8>> {
+    (0..samples).into_par_iter().find_map_any(|sample| {
+        // サンプルごとに異なるシードの乱数生成器を作るので、結果は実行ごとに変わらない
+        let mut rng = Pcg64Mcg::seed_from_u64(seed.wrapping_add(sample as u64));
+        let input: Vec<u64> = (0..network.len).map(|_| rng.gen()).collect();
+        check_batch(network, &input, u64::MAX)
+    })
+}
+
+// 2^len 通りの入力をすべて試して、ネットワークがソーティングネットワークかどうかを返す
+pub fn is_sorting_network(network: &Network) -> bool {
+    find_counterexample(network).is_none()
+}
+
+// 比較関数で要素数 len のスライスをソートする関数 sort から、実行した比較器の列を取り出す
+// sort は比較器のネットワーク（比較の結果だけで2要素を交換するかどうかを決め、
+// 比較の順序は入力によらない）を順次実行し、x[i] と x[j]（i < j）をこの順に比較するものとする。array.rs の展開したネットワークのように、
+// Network として書き出されていないソートも find_counterexample で検証できるようになる
+//
+// 要素には元の位置を渡す。比較関数が常に Equal を返せば要素は動かないので、
+// 比較した2要素の値がそのまま比較器の位置になる。向きは、n 番目の比較だけ Greater を返して
+// 交換されるかどうかで調べる（交換されたなら、小さい方を前に置く比較器）
+pub fn network_from_sort<S>(len: usize, sort: S) -> Network
+where
+    S: Fn(&mut [usize], &dyn Fn(&usize, &usize) -> Ordering),
+{
+    let pairs = RefCell::new(Vec::new());
+    let mut x: Vec<usize> = (0..len).collect();
+    sort(&mut x, &|a, b| {
+        pairs.borrow_mut().push((*a, *b));
+        Ordering::Equal
+    });
+
+    let comparators: Vec<Comparator> = pairs
+        .into_inner()
+        .into_iter()
+        .enumerate()
+        .map(|(n, (i, j))| {
+            let calls = Cell::new(0);
+            let mut x: Vec<usize> = (0..len).collect();
+            sort(&mut x, &|_, _| {
+                calls.set(calls.get() + 1);
+                if calls.get() == n + 1 {
+                    Ordering::Greater
+                } else {
+                    Ordering::Equal
+                }
+            });
+            let swapped = x[i] == j && x[j] == i;
+            // 動いた要素が x[i] と x[j] のほかにあれば、比較器のネットワークではない
+            assert!(
+                x.iter()
+                    .enumerate()
+                    .all(|(k, &v)| v == k || (swapped && (k == i || k == j))),
+                "sort is not a comparator network (comparison {}: x[{}] and x[{}])",
+                n,
+                i,
+                j
+            );
+            let order = if swapped {
+                SortOrder::Ascending
+            } else {
+                SortOrder::Descending
+            };
+            Comparator { i, j, order }
+        })
+        .collect();
+    Network::from_comparators(len, &comparators)
+}
+
+// 数値 base + k（k は 0..64）の wire ビット目を、k ビット目に並べた u64 を返す
+fn lanes_of_bit(base: u64, wire: usize) -> u64 {
+    // 下位 6ビットは k によって変わるので、決まったパターンになる
+    const PATTERNS: [u64; 6] = [
+        0xAAAA_AAAA_AAAA_AAAA,
+        0xCCCC_CCCC_CCCC_CCCC,
+        0xF0F0_F0F0_F0F0_F0F0,
+        0xFF00_FF00_FF00_FF00,
+        0xFFFF_0000_FFFF_0000,
+        0xFFFF_FFFF_0000_0000,
+    ];
+    if wire < 6 {
+        PATTERNS[wire]
+    } else if (base >> wire) & 1 == 1 {
+        u64::MAX
+    } else {
+        0
+    }
+}
+
+// 64通りの入力をネットワークに通し、ソートできなかった入力があればひとつ返す
+fn check_batch(network: &Network, input: &[u64], valid: u64) -> Option<Vec<u8>> {
+    let mut wires = input.to_vec();
+    for c in network.comparators() {
+        // 0-1入力では min は AND、max は OR になる
+        let (a, b) = (wires[c.i], wires[c.j]);
+        let (min, max) = (a & b, a | b);
+        match c.order {
+            SortOrder::Ascending => {
+                wires[c.i] = min;
+                wires[c.j] = max;
+            }
+            SortOrder::Descending => {
+                wires[c.i] = max;
+                wires[c.j] = min;
+            }
+        }
+    }
+
+    // 昇順になっていない入力のビットを集める（前のワイヤーが 1 で次のワイヤーが 0）
+    let unsorted = wires
+        .windows(2)
+        .fold(0, |acc, pair| acc | (pair[0] & !pair[1]))
+        & valid;
+    if unsorted == 0 {
+        return None;
+    }
+    let lane = unsorted.trailing_zeros();
+    Some(input.iter().map(|w| ((w >> lane) & 1) as u8).collect())
+}
+
+// このモジュールは cargo test を実行したときのみコンパイルされる
+#[cfg(test)]
+mod tests {
+    use super::{
+        find_counterexample, find_counterexample_random, is_sorting_network, network_from_sort,
+    };
+    use crate::network::{Comparator, Network};
+    use crate::utils::is_sorted_ascending;
+    use crate::SortOrder::*;
+    use crate::{iterative, third};
+
+    #[test]
+    fn bitonic_networks_sort_all_inputs() {
+        for len in 0..=20 {
+            assert!(is_sorting_network(&Network::bitonic(len)), "len: {}", len);
+        }
+    }
+
+    #[test]
+    fn odd_even_networks_sort_all_inputs() {
+        for len in 0..=20 {
+            assert!(is_sorting_network(&Network::odd_even(len)), "len: {}", len);
+        }
+    }
+
+    #[test]
+    fn large_networks_pass_random_check() {
+        for &len in &[100, 1000, 1500] {
+            assert_eq!(
+                find_counterexample_random(&Network::bitonic(len), 64, 0),
+                None
+            );
+            assert_eq!(
+                find_counterexample_random(&Network::odd_even(len), 64, 0),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn broken_network_has_counterexample() {
+        // バイトニックソートのネットワークから比較器をひとつ取り除く
+        let network = Network::bitonic(16);
+        let mut comparators: Vec<Comparator> = network.comparators().cloned().collect();
+        comparators.remove(comparators.len() / 2);
+        let broken = Network::from_comparators(16, &comparators);
+
+        let mut input = find_counterexample(&broken).expect("must not sort every input");
+        broken.apply(&mut input);
+        assert!(!is_sorted_ascending(&input));
+
+        assert!(find_counterexample_random(&broken, 64, 0).is_some());
+    }
+
+    #[test]
+    fn descending_network_is_not_ascending() {
+        // 比較器の向きも検証される
+        let network = Network::from_comparators(
+            2,
+            &[Comparator {
+                i: 0,
+                j: 1,
+                order: Descending,
+            }],
+        );
+        assert_eq!(find_counterexample(&network), Some(vec![1, 0]));
+    }
+
+    #[test]
+    fn network_from_sort_matches_network() {
+        // third::sort_by から取り出したネットワークは Network::bitonic と同じになる
+        for len in 0..=40 {
+            let network = network_from_sort(len, |x, comparator| {
+                third::sort_by(x, &|a, b| comparator(a, b)).unwrap();
+            });
+            assert_eq!(network, Network::bitonic(len), "len: {}", len);
+        }
+    }
+
+    #[test]
+    fn iterative_networks_sort_all_inputs() {
+        for len in 0..=20 {
+            let network = network_from_sort(len, |x, comparator| {
+                iterative::sort_by(x, &|a, b| comparator(a, b)).unwrap();
+            });
+            assert!(is_sorting_network(&network), "len: {}", len);
+        }
+    }
+}